@@ -1,209 +1,1562 @@
 // Compression by NobodyNada, with some small tweaks by Maddo,
 // to optimize a bit more for decompression speed compared to space.
-use crate::{Command, Reference};
+use crate::cost::{decode_cost, CostModel, SizeCostModel};
+use crate::decompress::{
+    commands, decode_unterminated, decompress_lenient, disassemble, validate, DecompressionError,
+};
+use crate::matcher::MatchIndex;
+use crate::{
+    Command, OwnedCommand, Reference, MAX_ABSOLUTE_OFFSET, MAX_COMMAND_LEN, MAX_INPUT_LEN,
+    MAX_INVERTED_RELATIVE_LEN, MAX_RELATIVE_DISTANCE,
+};
+use alloc::{vec, vec::Vec};
+#[cfg(feature = "thiserror")]
+use thiserror::Error;
+
+/// Options controlling the tradeoff between compression ratio and decompression speed.
+///
+/// The defaults favor decompression speed over output size.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompressOptions {
+    /// The minimum number of bytes a command must save over copying the same data literally in
+    /// order to be used.
+    ///
+    /// Higher values produce fewer, longer copy commands, which are faster to decompress but
+    /// compress less well. Defaults to 3.
+    pub min_savings: usize,
+
+    /// How to weigh commands against each other. Defaults to [`CompressMode::DecodeSpeed`].
+    pub mode: CompressMode,
+
+    /// Whether to stop looking for other commands when a maximum-length word fill is found.
+    ///
+    /// Long runs of repeated data are the worst case for the backreference search, so this can
+    /// speed up compression considerably. No command can be longer than the fill, so disabling
+    /// this can only find commands of the same length that the cost model rates as cheaper: for
+    /// instance, a byte fill when the run is a single repeated byte (one byte smaller than a word
+    /// fill), or a backreference under a custom [`CostModel`] that favors them. Defaults to `true`.
+    pub fill_early_return: bool,
+
+    /// Whether to encode runs of an even number of identical bytes as word fills rather than byte
+    /// fills.
+    ///
+    /// A byte fill is one byte smaller, so the cost models prefer it, but some decompressors fill
+    /// a word at a time and decode a word fill faster. Runs of an odd length are still encoded as
+    /// byte fills. Defaults to `false`.
+    pub prefer_word_fill: bool,
+
+    /// The maximum number of earlier positions to examine when searching for an absolute
+    /// backreference, for each of the as-is and inverted searches.
+    ///
+    /// Positions are examined from most to least recent. Lower values speed up compression of
+    /// large inputs at the cost of missing some matches; the output is valid for any value.
+    /// Defaults to `usize::MAX`, which examines every position.
+    pub max_chain: usize,
+
+    /// How far back from the current position to search for absolute backreferences.
+    ///
+    /// This bounds the window searched, unlike [`max_chain`](CompressOptions::max_chain), which
+    /// bounds the number of positions examined in it. Relative backreferences are always searched
+    /// up to [`MAX_RELATIVE_DISTANCE`] bytes back. Smaller values speed up compression of large
+    /// inputs at the cost of missing matches that are further apart, so they can only make the
+    /// output larger; it is valid for any value. Defaults to `usize::MAX`, which searches the whole
+    /// addressable window.
+    pub max_distance: usize,
+
+    /// Whether to use lazy matching: before using a command, check whether starting a command at
+    /// the next byte instead would save more, and if so, copy this byte literally.
+    ///
+    /// This usually improves the compression ratio at the cost of roughly doubling the time spent
+    /// searching for commands. Defaults to `false`.
+    pub lazy: bool,
+
+    /// Whether to decompress the output after compressing and check that it matches the input,
+    /// panicking if it doesn't.
+    ///
+    /// This guards against compressor bugs at the cost of a decompression per call. Use
+    /// [`compress_checked`] to get an error instead of a panic. Defaults to `false`.
+    pub verify: bool,
+
+    /// Whether to run a clean-up pass over the chosen commands before encoding them, which turns a
+    /// command back into literals when merging it with the copies around it makes the output
+    /// smaller.
+    ///
+    /// The parser judges each command on its own, but a command between two copies also costs
+    /// the header of the second copy, and copies longer than 32 bytes have 2-byte headers. So a
+    /// command that saves only a byte or two, such as the short fills and backreferences chosen
+    /// with [`CompressMode::SizeOptimal`], can make the output larger. Since a folded command is
+    /// decompressed as a literal copy instead, this only makes decompression slower where it
+    /// saves space. Defaults to `false`.
+    pub peephole: bool,
+
+    /// Whether to end the output with the `0xFF` stop command.
+    ///
+    /// Without it, the output is not self-terminating: a decompressor can't tell where it ends, so
+    /// it's only safe to store inside a container that records the length of each block (or that
+    /// terminates a sequence of blocks with a single `0xFF`). Decompress such a stream with
+    /// [`decompress_unterminated`](crate::decompress_unterminated). Defaults to `true`.
+    pub emit_stop: bool,
+
+    /// Whether to only use relative backreferences, which reach back at most
+    /// [`MAX_RELATIVE_DISTANCE`] bytes.
+    ///
+    /// Absolute backreferences address a fixed position from the start of the output, so a
+    /// stream without them decodes the same way no matter what output precedes it (for instance,
+    /// with [`decompress_with_prefix`](crate::decompress_with_prefix)). Repeats that are further
+    /// apart can no longer be matched, so the output can be much larger: data with repeats spread
+    /// across the whole block, such as level data, may compress to several times the size.
+    /// Defaults to `false`.
+    pub relative_only: bool,
+
+    /// The command types the compressor may use, for compatibility with decompressors that don't
+    /// implement all of them. Literal copies are always allowed. Defaults to [`CommandSet::all`].
+    pub allowed_commands: CommandSet,
+
+    /// The longest command the compressor may emit, for compatibility with decompressors that
+    /// mishandle long commands. Longer runs and literals are split into several commands.
+    ///
+    /// Must be between 1 and [`MAX_COMMAND_LEN`]; compressing with any other value panics. Defaults
+    /// to [`MAX_COMMAND_LEN`].
+    pub max_command_len: usize,
+}
+
+impl Default for CompressOptions {
+    fn default() -> Self {
+        CompressOptions {
+            min_savings: 3,
+            mode: CompressMode::DecodeSpeed,
+            fill_early_return: true,
+            prefer_word_fill: false,
+            max_chain: usize::MAX,
+            max_distance: usize::MAX,
+            lazy: false,
+            verify: false,
+            peephole: false,
+            emit_stop: true,
+            relative_only: false,
+            allowed_commands: CommandSet::all(),
+            max_command_len: MAX_COMMAND_LEN,
+        }
+    }
+}
+
+impl From<CompressMode> for CompressOptions {
+    /// Returns the default options for the given mode.
+    ///
+    /// [`CompressMode::SizeOptimal`] also lowers [`min_savings`](CompressOptions::min_savings) to
+    /// 1, so that any command that saves space is used.
+    fn from(mode: CompressMode) -> Self {
+        let min_savings = match mode {
+            CompressMode::DecodeSpeed => 3,
+            CompressMode::SizeOptimal => 1,
+        };
+        CompressOptions {
+            min_savings,
+            mode,
+            ..Default::default()
+        }
+    }
+}
+
+/// A set of command types, used to restrict which commands the compressor emits.
+///
+/// Sets can be combined with `|`. Literal copies are not part of the set, since they're always
+/// allowed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct CommandSet(u8);
+
+impl CommandSet {
+    /// [`Command::ByteFill`].
+    pub const BYTE_FILL: Self = CommandSet(1 << 0);
+    /// [`Command::WordFill`].
+    pub const WORD_FILL: Self = CommandSet(1 << 1);
+    /// [`Command::Incrementing`].
+    pub const INCREMENTING: Self = CommandSet(1 << 2);
+    /// Backreferences with a [`Reference::Absolute`] source.
+    pub const ABSOLUTE: Self = CommandSet(1 << 3);
+    /// Inverted backreferences with a [`Reference::Absolute`] source.
+    pub const INVERTED_ABSOLUTE: Self = CommandSet(1 << 4);
+    /// Backreferences with a [`Reference::Relative`] source.
+    pub const RELATIVE: Self = CommandSet(1 << 5);
+    /// Inverted backreferences with a [`Reference::Relative`] source.
+    pub const INVERTED_RELATIVE: Self = CommandSet(1 << 6);
+
+    /// Returns the set of all command types.
+    pub const fn all() -> Self {
+        CommandSet(0x7F)
+    }
+
+    /// Returns the empty set, which only allows literal copies.
+    pub const fn copy_only() -> Self {
+        CommandSet(0)
+    }
+
+    /// Returns the set of byte and word fills.
+    pub const fn copy_and_fill_only() -> Self {
+        CommandSet(Self::BYTE_FILL.0 | Self::WORD_FILL.0)
+    }
+
+    /// Returns whether every command type in `other` is in this set.
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Returns this set without the command types in `other`.
+    pub const fn without(self, other: Self) -> Self {
+        CommandSet(self.0 & !other.0)
+    }
+
+    /// Returns whether `cmd` may be used. Copies and stops are always allowed.
+    pub fn allows(self, cmd: &Command) -> bool {
+        let ty = match cmd {
+            Command::Copy(_) | Command::Stop => return true,
+            Command::ByteFill { data: _, len: _ } => Self::BYTE_FILL,
+            Command::WordFill { data: _, len: _ } => Self::WORD_FILL,
+            Command::Incrementing { start: _, len: _ } => Self::INCREMENTING,
+            Command::Backreference {
+                src: Reference::Absolute(_),
+                invert,
+                len: _,
+            } => {
+                if *invert {
+                    Self::INVERTED_ABSOLUTE
+                } else {
+                    Self::ABSOLUTE
+                }
+            }
+            Command::Backreference {
+                src: Reference::Relative(_),
+                invert,
+                len: _,
+            } => {
+                if *invert {
+                    Self::INVERTED_RELATIVE
+                } else {
+                    Self::RELATIVE
+                }
+            }
+        };
+        self.contains(ty)
+    }
+}
+
+impl Default for CommandSet {
+    fn default() -> Self {
+        Self::all()
+    }
+}
+
+impl core::ops::BitOr for CommandSet {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        CommandSet(self.0 | rhs.0)
+    }
+}
+
+/// The cost model used to choose between commands.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub enum CompressMode {
+    /// Treat command types that are slower to decompress (incrementing runs and backreferences) as
+    /// more expensive than their encoded size.
+    #[default]
+    DecodeSpeed,
+
+    /// Weigh commands purely by their encoded size, for the smallest output regardless of
+    /// decompression speed.
+    SizeOptimal,
+}
 
 /// Compresses the provided data.
+///
+/// An empty input compresses to just the terminating `0xFF`, which decompresses back to an empty
+/// output. The compressor only produces commands that [`assemble`] would accept (long literal runs
+/// are split into several copies), so this can't fail; use [`compress_checked`] to also verify the
+/// output.
+///
+/// Inputs of any size are accepted, but compression is best-effort beyond [`MAX_INPUT_LEN`]:
+/// absolute backreferences can't address data past the first 64 KiB, so repeats of that data are
+/// only found within relative range. Use [`try_compress`] to reject such inputs instead.
 pub fn compress(src: &[u8]) -> Vec<u8> {
+    compress_with(src, &CompressOptions::default())
+}
+
+/// Compresses the provided data, failing with [`CompressError::InputTooLarge`] if it is longer
+/// than [`MAX_INPUT_LEN`].
+pub fn try_compress(src: &[u8]) -> Result<Vec<u8>, CompressError> {
+    if src.len() > MAX_INPUT_LEN {
+        return Err(CompressError::InputTooLarge { len: src.len() });
+    }
+    Ok(compress(src))
+}
+
+/// Compresses the provided data using the given options.
+pub fn compress_with(src: &[u8], opts: &CompressOptions) -> Vec<u8> {
+    compress_with_cost_model(src, opts, &opts.mode)
+}
+
+/// Compresses the provided data using the given options and a custom cost model.
+///
+/// The cost model is used in place of [`CompressOptions::mode`], both to rank candidate commands
+/// and to decide whether a command saves enough over a literal copy.
+pub fn compress_with_cost_model<C: CostModel + ?Sized>(
+    src: &[u8],
+    opts: &CompressOptions,
+    cost: &C,
+) -> Vec<u8> {
     let mut dst = Vec::new();
+    compress_into_with(src, &mut dst, opts, cost);
+    dst
+}
+
+/// Compresses the provided data using the default options for the given mode.
+pub fn compress_with_mode(src: &[u8], mode: CompressMode) -> Vec<u8> {
+    compress_with(src, &mode.into())
+}
+
+/// Compresses the provided data, appending the compressed stream (including the terminating
+/// `0xFF`) to `dst`.
+///
+/// Existing contents of `dst` are left untouched, so several blocks can be compressed
+/// back-to-back into one buffer; record `dst.len()` before each call to find where each block
+/// starts.
+pub fn compress_into(src: &[u8], dst: &mut Vec<u8>) {
+    let opts = CompressOptions::default();
+    compress_into_with(src, dst, &opts, &opts.mode)
+}
+
+fn compress_into_with<C: CostModel + ?Sized>(
+    src: &[u8],
+    dst: &mut Vec<u8>,
+    opts: &CompressOptions,
+    cost: &C,
+) {
+    let start = dst.len();
+    let mut index = MatchIndex::new();
+    let mut candidates = Vec::new();
+    parse_with_peephole(src, opts, cost, &mut index, &mut candidates, |cmd| {
+        cmd.write(dst)
+    });
+    finish(src, dst, start, opts);
+}
+
+/// Terminates the stream written to `dst[start..]` and verifies it, as requested by `opts`.
+fn finish(src: &[u8], dst: &mut Vec<u8>, start: usize, opts: &CompressOptions) {
+    if opts.emit_stop {
+        Command::Stop.write(dst);
+    }
+    if opts.verify {
+        if let Err(e) = verify(src, &dst[start..], opts.emit_stop) {
+            panic!("{e}");
+        }
+    }
+}
+
+/// Compresses each of the provided blocks independently.
+///
+/// With the `rayon` feature enabled, blocks are compressed in parallel. Either way, the result is
+/// the same as calling [`compress`] on each block in order.
+pub fn compress_many(blocks: &[&[u8]]) -> Vec<Vec<u8>> {
+    #[cfg(feature = "rayon")]
+    {
+        use rayon::prelude::*;
+        blocks.par_iter().map(|block| compress(block)).collect()
+    }
+    #[cfg(not(feature = "rayon"))]
+    {
+        blocks.iter().map(|block| compress(block)).collect()
+    }
+}
+
+/// Compresses the provided data into several independent streams, each at most `max_chunk` bytes
+/// long (including its terminating `0xFF`).
+///
+/// Each chunk compresses as much of the remaining input as fits, so that one large piece of data
+/// can be stored in several smaller areas. Chunks are decompressed independently, and the original
+/// data is the concatenation of their decompressed outputs, in order. An empty input produces no
+/// chunks.
+///
+/// Backreferences can't cross chunk boundaries, so the total size is usually larger than that of
+/// a single stream.
+///
+/// # Panics
+///
+/// Panics if `max_chunk` is less than 3, the size of a stream holding a single literal byte.
+pub fn compress_into_chunks(src: &[u8], max_chunk: usize) -> Vec<Vec<u8>> {
+    assert!(
+        max_chunk >= 3,
+        "chunks must be able to hold at least one byte"
+    );
+    let mut chunks = Vec::new();
+    let mut rest = src;
+    while !rest.is_empty() {
+        let (chunk, len) = compress_prefix_within(rest, max_chunk);
+        chunks.push(chunk);
+        rest = &rest[len..];
+    }
+    chunks
+}
+
+/// Like [`compress_into_chunks`], but lays the chunks out back-to-back from `start_addr` so that
+/// none of them crosses a multiple of `bank_size`, for decompressors that can't read across a
+/// ROM bank boundary.
+///
+/// Returns each chunk along with the address it must be placed at. When too little space is left
+/// in a bank to hold a useful chunk, the next chunk starts at the beginning of the following bank.
+/// Addresses are linear, so for a LoROM image pass file offsets with a `bank_size` of `0x8000`.
+///
+/// # Panics
+///
+/// Panics if `bank_size` is less than 3, the size of a stream holding a single literal byte, or if
+/// the address of a chunk would overflow a `usize`.
+pub fn compress_into_chunks_at(
+    src: &[u8],
+    start_addr: usize,
+    bank_size: usize,
+) -> Vec<(usize, Vec<u8>)> {
+    assert!(
+        bank_size >= 3,
+        "banks must be able to hold at least one byte"
+    );
+    let mut chunks = Vec::new();
+    let mut addr = start_addr;
+    let mut rest = src;
+    while !rest.is_empty() {
+        let space = bank_size - addr % bank_size;
+        if space < 3 {
+            addr = addr.checked_add(space).expect("chunk address overflow");
+            continue;
+        }
+        let (chunk, len) = compress_prefix_within(rest, space);
+        let next = addr.checked_add(chunk.len());
+        chunks.push((addr, chunk));
+        addr = next.expect("chunk address overflow");
+        rest = &rest[len..];
+    }
+    chunks
+}
+
+/// Compresses the longest prefix of `src` whose stream is at most `max_len` bytes long, returning
+/// the stream and the length of the prefix. `max_len` must be at least 3.
+fn compress_prefix_within(src: &[u8], max_len: usize) -> (Vec<u8>, usize) {
+    let mut best = (compress(&src[..1]), 1);
+
+    // Double the prefix until it no longer fits, starting from `max_len` bytes, about what fits
+    // even as literals. This keeps the work for each chunk in proportion to the chunk's own input,
+    // rather than to the whole rest of `src`.
+    let (mut lo, mut hi) = (1, src.len() + 1);
+    let mut probe = max_len.min(src.len());
+    while probe > lo {
+        let candidate = compress(&src[..probe]);
+        if candidate.len() > max_len {
+            hi = probe;
+            break;
+        }
+        lo = probe;
+        best = (candidate, probe);
+        probe = (probe * 2).min(src.len());
+    }
+
+    // Binary search for the longest prefix that fits. Compressed size isn't strictly monotonic in
+    // the input length, but the prefix we end up with is always one that was checked to fit.
+    while hi - lo > 1 {
+        let mid = lo + (hi - lo) / 2;
+        let candidate = compress(&src[..mid]);
+        if candidate.len() <= max_len {
+            lo = mid;
+            best = (candidate, mid);
+        } else {
+            hi = mid;
+        }
+    }
+    best
+}
+
+/// Compresses the provided data, falling back to storing it as literal copies if compression
+/// doesn't make it smaller.
+///
+/// Returns whether the data was compressed, along with the stream. Either way, the result is a
+/// valid stream that decompresses to `src`; the flag lets a container format record which encoding
+/// was used. The stored form is `src` split into copy commands of up to [`MAX_COMMAND_LEN`] bytes,
+/// followed by a stop, so it's exactly [`compress_bound`] bytes long.
+pub fn compress_or_store(src: &[u8]) -> (bool, Vec<u8>) {
+    let compressed = compress(src);
+    if compressed.len() < compress_bound(src.len()) {
+        return (true, compressed);
+    }
+
+    let mut stored = Vec::with_capacity(compress_bound(src.len()));
+    emit_copy(src, MAX_COMMAND_LEN, &mut |cmd| cmd.write(&mut stored));
+    Command::Stop.write(&mut stored);
+    (false, stored)
+}
+
+/// Compresses the provided data, returning the result only if it's strictly smaller than `src`.
+///
+/// Compression doesn't always shrink data: incompressible input grows by a few bytes of command
+/// headers (see [`compress_bound`]). This is for callers that store data compressed only when it
+/// helps, and raw otherwise. Unlike [`compress_or_store`], the comparison is against the raw data
+/// itself rather than a stored stream.
+pub fn compress_if_smaller(src: &[u8]) -> Option<Vec<u8>> {
+    let compressed = compress(src);
+    (compressed.len() < src.len()).then_some(compressed)
+}
+
+/// Compresses the provided data, failing if the result is larger than `budget` bytes (including the
+/// terminating `0xFF`).
+///
+/// If the output of [`compress`] doesn't fit, this tries again with [`compress_optimal`], which is
+/// much slower but produces smaller output. If that doesn't fit either, the error reports how
+/// many bytes over the budget it is, so the caller can decide how much data to trim.
+pub fn compress_within(src: &[u8], budget: usize) -> Result<Vec<u8>, CompressError> {
+    let compressed = compress(src);
+    if compressed.len() <= budget {
+        return Ok(compressed);
+    }
+    let compressed = compress_optimal(src);
+    if compressed.len() <= budget {
+        Ok(compressed)
+    } else {
+        Err(CompressError::OverBudget {
+            size: compressed.len(),
+            budget,
+        })
+    }
+}
+
+/// Compresses the provided data, then checks that the output decompresses back to the input.
+///
+/// This is [`compress`] with [`verify`](CompressOptions::verify) enabled, except that a mismatch
+/// is returned as [`CompressError::RoundtripMismatch`] rather than causing a panic. A mismatch can
+/// only be caused by a bug in the compressor.
+pub fn compress_checked(src: &[u8]) -> Result<Vec<u8>, CompressError> {
+    let dst = compress(src);
+    verify(src, &dst, true)?;
+    Ok(dst)
+}
+
+/// Checks that `compressed` decompresses to `src`. If `terminated` is false, the stream is
+/// expected to end without a `Stop`.
+pub(crate) fn verify(src: &[u8], compressed: &[u8], terminated: bool) -> Result<(), CompressError> {
+    let (output, err) = if terminated {
+        decompress_lenient(compressed)
+    } else {
+        let mut output = Vec::new();
+        let err = decode_unterminated(compressed, &mut output).err();
+        (output, err)
+    };
+    if err.is_none() && output == src {
+        return Ok(());
+    }
+    let offset = core::iter::zip(src, &output)
+        .position(|(a, b)| a != b)
+        .unwrap_or(core::cmp::min(src.len(), output.len()));
+    Err(CompressError::RoundtripMismatch { offset })
+}
+
+/// A compressor that keeps its internal buffers between calls.
+///
+/// Compressing many blocks with one `Compressor` avoids allocating a fresh match index, candidate
+/// list and output buffer for each block. The output is the same as [`compress_with`] with the same options.
+#[derive(Debug, Clone)]
+pub struct Compressor {
+    opts: CompressOptions,
+    index: MatchIndex,
+    candidates: Vec<Command<'static>>,
+    output: Vec<u8>,
+}
+
+impl Compressor {
+    /// Creates a compressor with the default options.
+    pub fn new() -> Self {
+        Self::with_options(CompressOptions::default())
+    }
+
+    /// Creates a compressor with the given options.
+    pub fn with_options(opts: CompressOptions) -> Self {
+        Compressor {
+            opts,
+            index: MatchIndex::new(),
+            candidates: Vec::new(),
+            output: Vec::new(),
+        }
+    }
+
+    /// Compresses the provided data, returning a view of the compressed stream.
+    ///
+    /// The returned data is overwritten by the next call.
+    pub fn compress(&mut self, src: &[u8]) -> &[u8] {
+        self.output.clear();
+        self.index.clear();
+        parse_with_peephole(
+            src,
+            &self.opts,
+            &self.opts.mode,
+            &mut self.index,
+            &mut self.candidates,
+            |cmd| cmd.write(&mut self.output),
+        );
+        finish(src, &mut self.output, 0, &self.opts);
+        &self.output
+    }
+
+    /// Releases the memory held by the internal buffers.
+    pub fn reset(&mut self) {
+        self.index = MatchIndex::new();
+        self.candidates = Vec::new();
+        self.output = Vec::new();
+    }
+}
+
+impl Default for Compressor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Returns the largest possible output of [`compress`] for an input of `input_len` bytes.
+///
+/// This is the size of storing the input entirely as literal copies: one extended copy command
+/// (2 header bytes) per [`MAX_COMMAND_LEN`] bytes, a 1- or 2-byte header for the
+/// remainder, and the terminating `0xFF`. A command is only used when it encodes in fewer bytes than
+/// it saves, so the default compressor never does worse than this. The same bound holds for
+/// [`compress_optimal`], but not necessarily for other options or cost models.
+pub fn compress_bound(input_len: usize) -> usize {
+    let full_chunks = input_len / MAX_COMMAND_LEN;
+    let header = match input_len % MAX_COMMAND_LEN {
+        0 => 0,
+        1..=32 => 1,
+        _ => 2,
+    };
+    input_len + full_chunks * 2 + header + 1
+}
+
+/// Compresses the provided data, also returning statistics about the commands used.
+pub fn compress_with_stats(src: &[u8]) -> (Vec<u8>, CompressStats) {
+    let opts = CompressOptions::default();
+    let mut dst = Vec::new();
+    let mut stats = CompressStats {
+        input_len: src.len(),
+        unreachable_bytes: src.len().saturating_sub(MAX_ABSOLUTE_OFFSET + 1),
+        ..Default::default()
+    };
+    let mut index = MatchIndex::new();
+    parse(
+        src,
+        0,
+        &opts,
+        &opts.mode,
+        &mut index,
+        &mut Vec::new(),
+        |cmd| {
+            stats.record(&cmd);
+            cmd.write(&mut dst)
+        },
+    );
+    Command::Stop.write(&mut dst);
+    stats.output_len = dst.len();
+    (dst, stats)
+}
+
+/// Compresses the provided data, also returning the achieved [`compression_ratio`].
+pub fn compress_reporting(src: &[u8]) -> (Vec<u8>, f64) {
+    let dst = compress(src);
+    let ratio = compression_ratio(src.len(), dst.len());
+    (dst, ratio)
+}
+
+/// Returns `compressed / original`, the size of compressed data relative to the original data
+/// (lower is better).
+///
+/// Returns 1 if `original` is zero.
+pub fn compression_ratio(original: usize, compressed: usize) -> f64 {
+    if original == 0 {
+        1.0
+    } else {
+        compressed as f64 / original as f64
+    }
+}
+
+/// Compresses the provided data, allowing backreferences into a dictionary of data shared between
+/// the compressor and decompressor.
+///
+/// The dictionary is treated as if it had already been decompressed immediately before `src`: an
+/// absolute backreference to address `n` refers to byte `n` of `dict ++ src`, and relative
+/// backreferences may reach back into the end of the dictionary. The result must be decompressed
+/// with [`decompress_with_dictionary`](crate::decompress_with_dictionary) using the same
+/// dictionary.
+///
+/// Absolute addresses are 16 bits, so only the first 64 KiB of the dictionary and output combined
+/// can be the target of an absolute backreference.
+pub fn compress_with_dictionary(src: &[u8], dict: &[u8]) -> Vec<u8> {
+    let opts = CompressOptions::default();
+    let data = [dict, src].concat();
+    let mut dst = Vec::new();
+    parse(
+        &data,
+        dict.len(),
+        &opts,
+        &opts.mode,
+        &mut MatchIndex::new(),
+        &mut Vec::new(),
+        |cmd| cmd.write(&mut dst),
+    );
+    Command::Stop.write(&mut dst);
+    dst
+}
+
+/// Statistics about the commands chosen by the compressor.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CompressStats {
+    pub copy: CommandStats,
+    pub byte_fill: CommandStats,
+    pub word_fill: CommandStats,
+    pub incrementing: CommandStats,
+    pub absolute_backreference: CommandStats,
+    pub relative_backreference: CommandStats,
 
-    let mut i = 0;
-    let mut prev_copy = Vec::new();
+    /// The length of the uncompressed input.
+    pub input_len: usize,
+
+    /// The length of the compressed output, including the terminating `0xFF`.
+    pub output_len: usize,
+
+    /// The number of input bytes past the first 64 KiB, which absolute backreferences can't
+    /// address.
+    ///
+    /// Data there can only be repeated by relative backreferences, which reach back at most
+    /// [`MAX_RELATIVE_DISTANCE`] bytes, so a large value explains a poor ratio; splitting the
+    /// input into smaller blocks may help.
+    pub unreachable_bytes: usize,
+}
+
+/// Statistics about the commands of a single type.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CommandStats {
+    /// The number of commands emitted.
+    pub count: usize,
+
+    /// The number of bytes these commands occupy in the compressed output.
+    pub compressed_bytes: usize,
+
+    /// The number of bytes these commands produce when decompressed.
+    pub decompressed_bytes: usize,
+}
+
+impl CompressStats {
+    /// Returns the size of the compressed output relative to the input (lower is better).
+    ///
+    /// Returns 1 for an empty input.
+    pub fn ratio(&self) -> f64 {
+        compression_ratio(self.input_len, self.output_len)
+    }
+
+    fn record(&mut self, cmd: &Command) {
+        let stats = match cmd {
+            Command::Copy(_) => &mut self.copy,
+            Command::ByteFill { data: _, len: _ } => &mut self.byte_fill,
+            Command::WordFill { data: _, len: _ } => &mut self.word_fill,
+            Command::Incrementing { start: _, len: _ } => &mut self.incrementing,
+            Command::Backreference {
+                src: Reference::Absolute(_),
+                invert: _,
+                len: _,
+            } => &mut self.absolute_backreference,
+            Command::Backreference {
+                src: Reference::Relative(_),
+                invert: _,
+                len: _,
+            } => &mut self.relative_backreference,
+            Command::Stop => return,
+        };
+        stats.count += 1;
+        stats.compressed_bytes += cmd.encoded_len();
+        stats.decompressed_bytes += cmd.len();
+    }
+}
+
+/// Chooses the commands to encode `src[start..]` with, passing each one to `emit` (not including
+/// the final `Stop`). Backreferences may refer to `src[..start]`, as if it had already been
+/// decompressed.
+fn parse<'a, C: CostModel + ?Sized>(
+    src: &'a [u8],
+    start: usize,
+    opts: &CompressOptions,
+    cost: &C,
+    index: &mut MatchIndex,
+    candidates: &mut Vec<Command<'static>>,
+    mut emit: impl FnMut(Command<'a>),
+) {
+    let max_len = opts.max_command_len;
+    assert!(
+        (1..=MAX_COMMAND_LEN).contains(&max_len),
+        "max_command_len must be between 1 and MAX_COMMAND_LEN"
+    );
+    let mut i = start;
+    let mut copy_start = start;
+    let mut lookahead = None;
+    let mut run = None;
     while i < src.len() {
-        let best = find_best(src, i);
-        // We consider that the new command has to save at least 3 bytes to be worthwhile over a copy.
-        // It could save space with only 2 (or possibly 1) byte, but decompression will
-        // be faster by using a larger copy block.
-        if best.len() >= best.cost() + 3 {
-            if !prev_copy.is_empty() {
-                Command::Copy(&prev_copy[..]).write(&mut dst);
-                prev_copy = Vec::new();
-            }
-            best.write(&mut dst);
+        // After a maximum-length command, the same command often covers the next block of input
+        // too (for example, in long runs of repeated data). Check for that before searching.
+        let best = lookahead
+            .take()
+            .or_else(|| run.take().and_then(|cmd| continue_run(src, i, cmd)))
+            .or_else(|| find_best(src, i, opts, cost, index, candidates));
+        // By default, we consider that the new command has to save at least 3 bytes to be
+        // worthwhile over a copy. It could save space with only 2 (or possibly 1) byte, but
+        // decompression will be faster by using a larger copy block.
+        if let Some(best) = best.filter(|best| best.len() >= cost.cost(best) + opts.min_savings) {
+            if opts.lazy && i + 1 < src.len() {
+                // Check whether we'd be better off emitting this byte as a literal and starting
+                // a command at the next byte instead. Doing so may need an extra copy header.
+                let header = (copy_start == i) as isize;
+                let next = find_best(src, i + 1, opts, cost, index, candidates)
+                    .filter(|next| savings(next, cost) - header > savings(&best, cost));
+                if next.is_some() {
+                    lookahead = next;
+                    i += 1;
+                    continue;
+                }
+            }
+
+            emit_copy(&src[copy_start..i], max_len, &mut emit);
+            emit(best);
+            if best.len() == max_len {
+                run = Some(best);
+            }
             i += best.len();
+            copy_start = i;
         } else {
-            prev_copy.push(src[i]);
             i += 1;
         }
     }
 
-    if !prev_copy.is_empty() {
-        Command::Copy(&prev_copy[..]).write(&mut dst);
+    emit_copy(&src[copy_start..i], max_len, &mut emit);
+}
+
+/// Returns a command of the same kind and length as `cmd` that continues it at `i`, if it produces
+/// the same output as `src[i..]`. Only fills, incrementing runs and relative backreferences can be
+/// continued.
+fn continue_run<'a>(src: &[u8], i: usize, cmd: Command<'a>) -> Option<Command<'a>> {
+    let len = cmd.len();
+    let next = src.get(i..i + len)?;
+    let cmd = match cmd {
+        // A word fill of odd length ends partway through a word
+        Command::WordFill { data, len } if len % 2 == 1 => Command::WordFill {
+            data: data.swap_bytes(),
+            len,
+        },
+        Command::Incrementing { start, len } => Command::Incrementing {
+            start: start.wrapping_add(len as u8),
+            len,
+        },
+        cmd => cmd,
+    };
+    let repeats = match cmd {
+        Command::ByteFill { data, len: _ } => next.iter().all(|&x| x == data),
+        Command::WordFill { data, len: _ } => next
+            .iter()
+            .zip(data.to_le_bytes().iter().cycle())
+            .all(|(a, b)| a == b),
+        Command::Incrementing { start, len: _ } => next
+            .iter()
+            .enumerate()
+            .all(|(k, &x)| x == start.wrapping_add(k as u8)),
+        Command::Backreference {
+            src: Reference::Relative(distance),
+            invert,
+            len: _,
+        } => {
+            let mask = if invert { 0xFF } else { 0 };
+            core::iter::zip(next, &src[i - distance as usize..]).all(|(&a, &b)| a == b ^ mask)
+        }
+        _ => false,
+    };
+    repeats.then_some(cmd)
+}
+
+/// Runs [`parse`] on `src`, followed by [`peephole`] if enabled in `opts`.
+fn parse_with_peephole<'a, C: CostModel + ?Sized>(
+    src: &'a [u8],
+    opts: &CompressOptions,
+    cost: &C,
+    index: &mut MatchIndex,
+    candidates: &mut Vec<Command<'static>>,
+    emit: impl FnMut(Command<'a>),
+) {
+    if opts.peephole {
+        let mut commands = Vec::new();
+        parse(src, 0, opts, cost, index, candidates, |cmd| {
+            commands.push(cmd)
+        });
+        peephole(src, 0, opts.max_command_len, &commands, emit);
+    } else {
+        parse(src, 0, opts, cost, index, candidates, emit);
     }
+}
 
-    Command::Stop.write(&mut dst);
+/// Re-encodes `commands`, which must encode `src[start..]`, as literals wherever that is smaller.
+///
+/// Each command other than a copy is folded into the literals around it if a single run of copies
+/// (of up to `max_len` bytes each) for the literals before it, its own output and the literals
+/// after it encodes to fewer bytes than keeping it. Adjacent copies are merged, and empty ones
+/// dropped.
+pub(crate) fn peephole<'a>(
+    src: &'a [u8],
+    start: usize,
+    max_len: usize,
+    commands: &[Command<'a>],
+    mut emit: impl FnMut(Command<'a>),
+) {
+    let copy_len = |data: &[u8]| -> usize {
+        data.chunks(max_len)
+            .map(|chunk| Command::Copy(chunk).encoded_len())
+            .sum()
+    };
+
+    let mut i = start;
+    let mut copy_start = start;
+    for (k, &cmd) in commands.iter().enumerate() {
+        if !matches!(cmd, Command::Copy(_)) {
+            let end = i + cmd.len();
+            let following: usize = commands[k + 1..]
+                .iter()
+                .take_while(|cmd| matches!(cmd, Command::Copy(_)))
+                .map(Command::len)
+                .sum();
+            let kept = copy_len(&src[copy_start..i])
+                + cmd.encoded_len()
+                + copy_len(&src[end..end + following]);
+            let folded = copy_len(&src[copy_start..end + following]);
+            if folded >= kept {
+                emit_copy(&src[copy_start..i], max_len, &mut emit);
+                emit(cmd);
+                copy_start = end;
+            }
+        }
+        i += cmd.len();
+    }
+    emit_copy(&src[copy_start..i], max_len, &mut emit);
+}
+
+/// Returns the number of bytes saved by encoding a command rather than copying its output.
+fn savings<C: CostModel + ?Sized>(cmd: &Command, cost: &C) -> isize {
+    cmd.len() as isize - cost.cost(cmd) as isize
+}
 
+/// Compresses the provided data, choosing commands to minimize the size of the output.
+///
+/// Rather than greedily picking the best-looking command at each position like [`compress`], this
+/// considers every candidate command (and every shorter prefix of it) at every position, and finds
+/// the sequence of commands with the smallest total encoded size. This is considerably slower than
+/// [`compress`], and the output is slower to decompress than with the default options, so it's
+/// best suited to data that is compressed once and shipped.
+pub fn compress_optimal(src: &[u8]) -> Vec<u8> {
+    let opts = CompressOptions::from(CompressMode::SizeOptimal);
+    let mut dst = Vec::new();
+    parse_optimal(src, &opts, &SizeCostModel, |cmd| cmd.write(&mut dst));
+    Command::Stop.write(&mut dst);
     dst
 }
 
-fn get_candidates(src: &[u8], i: usize) -> Vec<Command> {
-    let mut candidates = vec![];
+/// Compresses the provided data with several strategies and returns the smallest output.
+///
+/// This tries the default options, [`CompressMode::SizeOptimal`] with and without
+/// [`lazy`](CompressOptions::lazy) matching, and [`compress_optimal`]. Outputs of the same size
+/// are ranked by [`decode_cost`], so the fastest one to decompress is kept, and any remaining tie
+/// goes to the first strategy in that order. Since it compresses the data several times, including
+/// once with the slow optimal parser, this is only worthwhile for data that is compressed once
+/// and shipped.
+pub fn compress_best(src: &[u8]) -> Vec<u8> {
+    let size_optimal = CompressOptions::from(CompressMode::SizeOptimal);
+    let lazy = CompressOptions {
+        lazy: true,
+        ..size_optimal.clone()
+    };
+    [
+        compress(src),
+        compress_with(src, &size_optimal),
+        compress_with(src, &lazy),
+        compress_optimal(src),
+    ]
+    .into_iter()
+    .min_by_key(|dst| (dst.len(), decode_cost(dst).unwrap_or(usize::MAX)))
+    .unwrap()
+}
+
+/// Chooses the sequence of commands with the lowest total cost to encode `src`, passing each one to
+/// `emit` (not including the final `Stop`).
+fn parse_optimal<'a, C: CostModel + ?Sized>(
+    src: &'a [u8],
+    opts: &CompressOptions,
+    cost: &C,
+    mut emit: impl FnMut(Command<'a>),
+) {
+    // For each position, the lowest total cost of encoding everything before it, and the last
+    // command used to get there.
+    let mut best: Vec<(usize, Option<Command<'a>>)> = vec![(usize::MAX, None); src.len() + 1];
+    best[0].0 = 0;
+
+    let mut index = MatchIndex::new();
+    let mut candidates = Vec::new();
+    for i in 0..src.len() {
+        let base = best[i].0;
+        let mut relax = |cmd: Command<'a>| {
+            let total = base + cost.cost(&cmd);
+            let end = i + cmd.len();
+            if total < best[end].0 {
+                best[end] = (total, Some(cmd));
+            }
+        };
+
+        for len in 1..=core::cmp::min(src.len() - i, opts.max_command_len) {
+            relax(Command::Copy(&src[i..i + len]));
+        }
+        get_candidates(src, i, opts, cost, &mut index, true, &mut candidates);
+        for &cand in &candidates {
+            for len in 1..=cand.len() {
+                relax(cand.with_len(len));
+            }
+        }
+    }
+
+    let mut commands = Vec::new();
+    let mut i = src.len();
+    while i > 0 {
+        let cmd = best[i].1.unwrap();
+        commands.push(cmd);
+        i -= cmd.len();
+    }
+    for cmd in commands.into_iter().rev() {
+        emit(cmd);
+    }
+}
+
+/// Emits literal data, split into as many copy commands of up to `max_len` bytes as necessary.
+fn emit_copy<'a>(data: &'a [u8], max_len: usize, emit: &mut impl FnMut(Command<'a>)) {
+    for chunk in data.chunks(max_len) {
+        emit(Command::Copy(chunk));
+    }
+}
+
+/// Encodes a list of commands into a compressed stream.
+///
+/// A [`Stop`](OwnedCommand::Stop) command is appended unless the list already ends with one, so
+/// the output of [`disassemble`](crate::disassemble) can be passed back in unchanged. A stop
+/// anywhere else is an error, since the commands after it would never be decoded.
+pub fn assemble(commands: &[OwnedCommand]) -> Result<Vec<u8>, CompressError> {
+    let mut dst = Vec::new();
+    for (index, cmd) in commands.iter().enumerate() {
+        let cmd = cmd.as_command();
+        if cmd.len() > MAX_COMMAND_LEN {
+            return Err(CompressError::CommandTooLong { index });
+        }
+        if cmd == Command::Stop && index + 1 != commands.len() {
+            return Err(CompressError::EarlyStop { index });
+        }
+        if cmd.len() == 0 && cmd != Command::Stop {
+            return Err(CompressError::EmptyCommand { index });
+        }
+        if let Command::Backreference {
+            src: Reference::Relative(offset),
+            invert,
+            len,
+        } = cmd
+        {
+            if offset == 0 {
+                return Err(CompressError::InvalidRelativeOffset { index });
+            }
+            if invert && len > MAX_INVERTED_RELATIVE_LEN {
+                return Err(CompressError::InvertedRelativeTooLong { index });
+            }
+        }
+        cmd.write(&mut dst);
+    }
+
+    if commands.last() != Some(&OwnedCommand::Stop) {
+        Command::Stop.write(&mut dst);
+    }
+
+    Ok(dst)
+}
+
+/// Re-encodes a compressed stream, writing each command in its shortest form.
+///
+/// Other encoders may use the 2-byte extended header where the 1-byte form would fit. This
+/// produces the same commands with the shortest headers, which is also how [`compress`] writes
+/// them, so streams with the same commands compare equal after canonicalization. The commands
+/// themselves are unchanged, and any data after the terminating `0xFF` is dropped.
+///
+/// Fails if `src` is not a valid stream, as reported by [`validate`].
+pub fn canonicalize(src: &[u8]) -> Result<Vec<u8>, DecompressionError> {
+    let len = validate(src)?;
+    let mut dst = Vec::with_capacity(len);
+    for cmd in commands(src) {
+        cmd?.write(&mut dst);
+    }
+    Ok(dst)
+}
+
+/// Re-encodes a compressed stream with `delta` added to the address of every absolute
+/// backreference.
+///
+/// This is for moving a stream that refers to data decompressed before it, such as a dictionary or
+/// a shared prefix, when that data moves within the output. Relative backreferences and all other
+/// commands are unchanged, and each command is written in its shortest form, as with
+/// [`canonicalize`]. Backreferences aren't checked against the output, since they may point into
+/// data that isn't part of the stream.
+///
+/// Fails with [`DecompressionError::RebaseOutOfRange`] if an address would go below 0 or above
+/// [`MAX_ABSOLUTE_OFFSET`], with [`DecompressionError::RelativeZeroOffset`] for a relative
+/// backreference with a distance of zero, or with a parse error if `src` is malformed.
+pub fn rebase_absolute(src: &[u8], delta: i32) -> Result<Vec<u8>, DecompressionError> {
+    let mut dst = Vec::with_capacity(src.len());
+    for (offset, cmd) in disassemble(src)? {
+        let mut cmd = cmd.as_command();
+        // Not checked by disassemble, and can't be encoded
+        if let Command::Backreference {
+            src: Reference::Relative(0),
+            ..
+        } = cmd
+        {
+            return Err(DecompressionError::RelativeZeroOffset { offset });
+        }
+        if let Command::Backreference {
+            src: Reference::Absolute(addr),
+            invert,
+            len,
+        } = cmd
+        {
+            let address = addr as i64 + delta as i64;
+            let addr = u16::try_from(address)
+                .map_err(|_| DecompressionError::RebaseOutOfRange { offset, address })?;
+            cmd = Command::Backreference {
+                src: Reference::Absolute(addr),
+                invert,
+                len,
+            };
+        }
+        cmd.write(&mut dst);
+    }
+    Ok(dst)
+}
+
+/// Errors that can occur while encoding commands.
+///
+/// These are returned by the fallible entry points, such as [`assemble`] for commands built by
+/// hand, [`compress_checked`], [`compress_within`] and [`try_compress`]; [`compress`] never
+/// produces commands that would cause them.
+#[derive(Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "thiserror", derive(Error))]
+pub enum CompressError {
+    /// A command is longer than [`MAX_COMMAND_LEN`]. `index` is the position of the command in
+    /// the list.
+    #[cfg_attr(
+        feature = "thiserror",
+        error("Command {index} is longer than the maximum command length")
+    )]
+    CommandTooLong { index: usize },
+
+    /// A command other than a stop has a length of zero, which can't be encoded.
+    #[cfg_attr(feature = "thiserror", error("Command {index} has a length of zero"))]
+    EmptyCommand { index: usize },
+
+    /// A stop command appears before the end of the list, so the commands after it would be
+    /// ignored by the decompressor.
+    #[cfg_attr(
+        feature = "thiserror",
+        error("Command {index} is a stop before the end of the list")
+    )]
+    EarlyStop { index: usize },
+
+    /// An inverted relative backreference is longer than [`MAX_INVERTED_RELATIVE_LEN`].
+    #[cfg_attr(
+        feature = "thiserror",
+        error("Command {index} is an inverted relative backreference longer than 0x300 bytes")
+    )]
+    InvertedRelativeTooLong { index: usize },
+
+    /// A relative backreference has an offset of zero, which can't be decoded.
+    #[cfg_attr(
+        feature = "thiserror",
+        error("Command {index} is a relative backreference with an offset of zero")
+    )]
+    InvalidRelativeOffset { index: usize },
+
+    /// The compressed output did not decompress to the input. `offset` is the position of the
+    /// first byte of the input that was not reproduced.
+    #[cfg_attr(
+        feature = "thiserror",
+        error("Compressed output does not match the input at offset {offset}")
+    )]
+    RoundtripMismatch { offset: usize },
+
+    /// The compressed output is larger than the budget given to [`compress_within`]. `size` is the
+    /// size of the smallest output found, so it is `size - budget` bytes over.
+    #[cfg_attr(
+        feature = "thiserror",
+        error(
+            "Compressed output is {size} bytes, {} bytes over the budget of {budget} bytes",
+            .size - .budget
+        )
+    )]
+    OverBudget { size: usize, budget: usize },
+
+    /// The input to [`try_compress`] is longer than [`MAX_INPUT_LEN`].
+    #[cfg_attr(
+        feature = "thiserror",
+        error("Input of {len} bytes is longer than the maximum of 0x10000 bytes")
+    )]
+    InputTooLarge { len: usize },
+}
+
+#[cfg(not(feature = "thiserror"))]
+impl core::fmt::Display for CompressError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            CompressError::CommandTooLong { index } => {
+                write!(
+                    f,
+                    "Command {index} is longer than the maximum command length"
+                )
+            }
+            CompressError::EmptyCommand { index } => {
+                write!(f, "Command {index} has a length of zero")
+            }
+            CompressError::EarlyStop { index } => {
+                write!(f, "Command {index} is a stop before the end of the list")
+            }
+            CompressError::InvertedRelativeTooLong { index } => write!(
+                f,
+                "Command {index} is an inverted relative backreference longer than 0x300 bytes"
+            ),
+            CompressError::InvalidRelativeOffset { index } => write!(
+                f,
+                "Command {index} is a relative backreference with an offset of zero"
+            ),
+            CompressError::RoundtripMismatch { offset } => write!(
+                f,
+                "Compressed output does not match the input at offset {offset}"
+            ),
+            CompressError::OverBudget { size, budget } => write!(
+                f,
+                "Compressed output is {size} bytes, {} bytes over the budget of {budget} bytes",
+                size - budget
+            ),
+            CompressError::InputTooLarge { len } => write!(
+                f,
+                "Input of {len} bytes is longer than the maximum of 0x10000 bytes"
+            ),
+        }
+    }
+}
+
+#[cfg(not(feature = "thiserror"))]
+impl core::error::Error for CompressError {}
 
-    if src.len() - i >= 2 {
+/// Replaces the contents of `candidates` with the commands that could be used at position `i`: the
+/// longest run of each kind, and either the best backreference according to `cost` or, if
+/// `all_backreferences` is set, the longest backreference of each kind.
+///
+/// Candidates are never copies, so they don't borrow from `src`, and the same buffer can be reused
+/// across inputs.
+fn get_candidates<C: CostModel + ?Sized>(
+    src: &[u8],
+    i: usize,
+    opts: &CompressOptions,
+    cost: &C,
+    index: &mut MatchIndex,
+    all_backreferences: bool,
+    candidates: &mut Vec<Command<'static>>,
+) {
+    candidates.clear();
+    let allowed = opts.allowed_commands;
+    let max_len = opts.max_command_len;
+
+    if src.len() - i >= 2 && allowed.contains(CommandSet::WORD_FILL) {
         let word = u16::from_le_bytes([src[i], src[i + 1]]);
         let mut len = src[i..]
             .chunks_exact(2)
+            .take(max_len / 2)
             .take_while(|c| u16::from_le_bytes((*c).try_into().unwrap()) == word)
             .count()
             * 2;
 
-        // A word fill can have a partial last word
+        // A word fill can have a partial last word, which is its low byte: a run ending on the
+        // high byte ends with a complete word, which was counted above. (If the count stopped at
+        // the length limit instead, the min below keeps the extra byte out.) A fill can also
+        // start mid-word, since the word is taken from wherever the run starts: a run that begins
+        // one byte earlier is found as a fill of the swapped word when the parser is at that
+        // byte, so there's no need to extend fills backwards here.
         if src.get(i + len).copied() == Some(word as u8) {
             len += 1;
         }
 
-        let len = std::cmp::min(len, Command::MAX_LEN);
+        let len = core::cmp::min(len, max_len);
         candidates.push(Command::WordFill { data: word, len });
-        if len == Command::MAX_LEN {
+        if len == max_len && opts.fill_early_return {
             // Skip considering other block types if this is a max-size block:
             // This can speed up compression significantly, because large
             // blocks of repeated data would trigger worst-case slow behavior
             // in the backreference search.
-            return candidates;
+            return;
         }
     }
 
-    candidates.push(Command::ByteFill {
-        data: src[i],
-        len: std::cmp::min(
-            src[i..].iter().take_while(|&&x| x == src[i]).count(),
-            Command::MAX_LEN,
-        ),
-    });
+    let run_len = src[i..]
+        .iter()
+        .take(max_len)
+        .take_while(|&&x| x == src[i])
+        .count();
+    // An even-length run is covered exactly by the word fill found above
+    let prefer_word_fill =
+        opts.prefer_word_fill && run_len % 2 == 0 && allowed.contains(CommandSet::WORD_FILL);
+    if allowed.contains(CommandSet::BYTE_FILL) && !prefer_word_fill {
+        candidates.push(Command::ByteFill {
+            data: src[i],
+            len: run_len,
+        });
+    }
 
-    candidates.push(Command::Incrementing {
-        start: src[i],
-        len: std::cmp::min(
-            std::iter::zip(
-                std::iter::successors(Some(src[i]), |x| Some(x.wrapping_add(1))),
+    // A run that continues the previous byte can also be encoded as a backreference to it, which
+    // the decompressor copies one byte at a time. This is never cheaper than a byte fill under the
+    // built-in cost models, so it comes after the fill to lose ties, but a custom cost model may
+    // prefer it. The backreference search may find the same run at a longer distance.
+    if i > 0 && src[i - 1] == src[i] && allowed.contains(CommandSet::RELATIVE) {
+        candidates.push(Command::Backreference {
+            src: Reference::Relative(1),
+            invert: false,
+            len: run_len,
+        });
+    }
+
+    if allowed.contains(CommandSet::INCREMENTING) {
+        candidates.push(Command::Incrementing {
+            start: src[i],
+            len: core::iter::zip(
+                core::iter::successors(Some(src[i]), |x| Some(x.wrapping_add(1))),
                 src[i..].iter().copied(),
             )
+            .take(max_len)
             .take_while(|(a, b)| a == b)
             .count(),
-            Command::MAX_LEN,
-        ),
-    });
+        });
+    }
 
-    if let Some(cand) = find_best_backreference(src, i) {
+    if all_backreferences {
+        candidates.extend(
+            find_backreferences(src, i, opts, index)
+                .into_iter()
+                .flatten(),
+        );
+    } else if let Some(cand) = find_best_backreference(src, i, opts, cost, index) {
         candidates.push(cand);
     }
-
-    candidates
 }
 
-fn find_best(src: &[u8], i: usize) -> Command {
-    let mut candidates = get_candidates(src, i);
+fn find_best<C: CostModel + ?Sized>(
+    src: &[u8],
+    i: usize,
+    opts: &CompressOptions,
+    cost: &C,
+    index: &mut MatchIndex,
+    candidates: &mut Vec<Command<'static>>,
+) -> Option<Command<'static>> {
+    get_candidates(src, i, opts, cost, index, false, candidates);
 
     // We want to prioritize earlier candidates in case of ties, but max_by prioritizes last.
     // So reverse the order:
-    candidates.reverse();
+    candidates.iter().rev().copied().max_by(|a, b| {
+        // Compare a.len() / a.cost() against b.len() / b.cost(), using exact integer math
+        let a_ratio = a.len() * cost.cost(b);
+        let b_ratio = b.len() * cost.cost(a);
+        a_ratio.cmp(&b_ratio)
+    })
+}
 
-    candidates
+/// Returns the best backreference to use at position `i`: the longest one, and of those the
+/// cheapest according to `cost`.
+///
+/// Remaining ties prefer relative over absolute and as-is over inverted backreferences. With the
+/// built-in cost models, this order already puts the cheapest first: an inverted relative
+/// backreference needs the extended header, and an absolute one has a 2-byte operand, while
+/// inverting an absolute backreference costs nothing extra.
+fn find_best_backreference<C: CostModel + ?Sized>(
+    src: &[u8],
+    i: usize,
+    opts: &CompressOptions,
+    cost: &C,
+    index: &mut MatchIndex,
+) -> Option<Command<'static>> {
+    // find_backreferences returns candidates in order of preference
+    find_backreferences(src, i, opts, index)
         .into_iter()
-        .max_by(|a, b| {
-            let a = a.len() as f32 / a.cost() as f32;
-            let b = b.len() as f32 / b.cost() as f32;
-            a.partial_cmp(&b).unwrap()
-        })
-        .unwrap()
+        .flatten()
+        .rev()
+        .max_by_key(|cmd| (cmd.len(), core::cmp::Reverse(cost.cost(cmd))))
 }
 
-fn find_best_backreference(src: &[u8], i: usize) -> Option<Command> {
-    let mut best_relative = (0, false, 0); // a (j, inv, len) pair
-    let farthest_relative = i - std::cmp::min(i, 255);
-    for j in farthest_relative..i {
-        let (inv, mut len) = backreference_at(src, i, j);
-        if inv {
-            // Maximum length for an inverted relative backreference is 0x300
-            // due to collision with stop command
-            len = len.min(0x300);
-        }
-        // if all else is equal, non-inverted relative matches save a byte (because relative
-        // inverted can only be encoded as an extended command)
-        if len > best_relative.2 || len == best_relative.2 && !inv && best_relative.1 {
-            best_relative = (j, inv, len);
+/// Returns the longest backreference of each kind (as-is or inverted, relative or absolute) that
+/// can be used at position `i`, in that order, or `None` where there is no match of that kind.
+///
+/// Every other match is dominated by one of these: a shorter match of the same kind encodes to the
+/// same size as a prefix of the longest one, so parsers that consider every prefix of these
+/// candidates lose nothing (unless a custom cost model weighs backreferences by their source).
+/// The search is bounded by [`max_chain`](CompressOptions::max_chain), and respects
+/// [`allowed_commands`](CompressOptions::allowed_commands) and
+/// [`relative_only`](CompressOptions::relative_only).
+pub(crate) fn find_backreferences(
+    src: &[u8],
+    i: usize,
+    opts: &CompressOptions,
+    index: &mut MatchIndex,
+) -> [Option<Command<'static>>; 4] {
+    let allowed = opts.allowed_commands;
+    let max_len = opts.max_command_len;
+    let max_inverted_relative_len = core::cmp::min(max_len, MAX_INVERTED_RELATIVE_LEN);
+    let allows_relative = |inv| {
+        allowed.contains(if inv {
+            CommandSet::INVERTED_RELATIVE
+        } else {
+            CommandSet::RELATIVE
+        })
+    };
+    let allows_absolute = |inv| {
+        !opts.relative_only
+            && allowed.contains(if inv {
+                CommandSet::INVERTED_ABSOLUTE
+            } else {
+                CommandSet::ABSOLUTE
+            })
+    };
+
+    // The longest (j, len) match of each kind, indexed by whether it's inverted. In case of ties,
+    // the first match found is kept.
+    let mut best_relative = [(0, 0); 2];
+    let farthest_relative = i - core::cmp::min(i, MAX_RELATIVE_DISTANCE);
+    let nearest_relative = if allows_relative(false) || allows_relative(true) {
+        i
+    } else {
+        farthest_relative
+    };
+    for j in farthest_relative..nearest_relative {
+        // Inverted relative backreferences are shorter due to collision with stop command
+        let (inv, len) = backreference_at(src, i, j, max_len, max_inverted_relative_len);
+        if allows_relative(inv) && len > best_relative[inv as usize].1 {
+            best_relative[inv as usize] = (j, len);
+            if len == max_len {
+                // no later match can be better
+                break;
+            }
         }
     }
 
-    let mut best_absolute = (0, false, 0); // a (j, inv, len) pair
-    for j in 0..std::cmp::min(farthest_relative, (u16::MAX as usize) + 1) {
-        let (inv, len) = backreference_at(src, i, j);
-        if len > best_absolute.2 {
-            best_absolute = (j, inv, len);
+    let mut best_absolute = [(0, 0); 2];
+    index.extend(
+        src,
+        core::cmp::min(farthest_relative, MAX_ABSOLUTE_OFFSET + 1),
+    );
+    // An absolute match can't beat a maximum-length relative match, and a match must be at least
+    // three bytes long to be found in the index.
+    if (allows_absolute(false) || allows_absolute(true))
+        && best_relative[0].1 < max_len
+        && i + 2 < src.len()
+    {
+        let seq = [src[i], src[i + 1], src[i + 2]];
+        for invert in [false, true] {
+            if !allows_absolute(invert) {
+                continue;
+            }
+            let key = if invert { seq.map(|x| !x) } else { seq };
+            let candidates = index.positions(key).take(opts.max_chain);
+            // Positions are examined from most to least recent, so the rest are too far away.
+            for j in candidates.take_while(|&j| i - j <= opts.max_distance) {
+                // Most candidates can be ruled out by checking whether they extend past the
+                // current best match of the same kind, without comparing the whole match. In case
+                // of ties, the most recent match is kept.
+                let inv = src[i] != src[j];
+                let (_, len) = best_absolute[inv as usize];
+                if !allows_absolute(inv)
+                    || len > 0
+                        && (i + len >= src.len()
+                            || src[j + len] ^ if inv { 0xFF } else { 0 } != src[i + len])
+                {
+                    continue;
+                }
+
+                let (_, len) = backreference_at(src, i, j, max_len, max_len);
+                if len > best_absolute[inv as usize].1 {
+                    best_absolute[inv as usize] = (j, len);
+                }
+                if best_absolute[invert as usize].1 == max_len {
+                    break;
+                }
+            }
         }
     }
 
-    match (best_relative, best_absolute) {
-        // No match found
-        ((_, _, 0), (_, _, 0)) => None,
-
-        // Relative match is best
-        ((j, invert, len), abs) if len >= abs.2 => Some(Command::Backreference {
+    let relative = |(j, len): (usize, usize), invert| {
+        (len > 0).then(|| Command::Backreference {
             src: Reference::Relative((i - j).try_into().unwrap()),
             invert,
             len,
-        }),
-
-        // Absolute is best
-        (_, (j, invert, len)) => Some(Command::Backreference {
+        })
+    };
+    let absolute = |(j, len): (usize, usize), invert| {
+        (len > 0).then(|| Command::Backreference {
             src: Reference::Absolute(j.try_into().unwrap()),
             invert,
             len,
-        }),
-    }
+        })
+    };
+    [
+        relative(best_relative[0], false),
+        relative(best_relative[1], true),
+        absolute(best_absolute[0], false),
+        absolute(best_absolute[1], true),
+    ]
 }
 
-fn backreference_at(src: &[u8], i: usize, j: usize) -> (bool, usize) {
-    let len = std::iter::zip(src[i..].iter().copied(), src[j..].iter().copied())
-        .take_while(|(a, b)| *a == *b)
+/// Returns the length of the match between `src[i..]` and `src[j..]`, up to `max_len` bytes, and
+/// whether the match is inverted. Inverted matches are capped at `max_inverted_len`.
+///
+/// The match may run past `i`, since the decompressor copies one byte at a time and so can copy
+/// bytes written by the backreference itself. This is what lets a short distance encode a long
+/// repeating pattern.
+fn backreference_at(
+    src: &[u8],
+    i: usize,
+    j: usize,
+    max_len: usize,
+    max_inverted_len: usize,
+) -> (bool, usize) {
+    // The first byte can match either as-is or inverted, but not both
+    let invert = src[i] != src[j];
+    let mask = if invert { 0xFF } else { 0 };
+    let max_len = if invert { max_inverted_len } else { max_len };
+    let len = core::iter::zip(src[i..].iter().copied(), src[j..].iter().copied())
+        .take(max_len)
+        .take_while(|(a, b)| *a == *b ^ mask)
         .count();
-    let len = std::cmp::min(len, Command::MAX_LEN);
-    if len > 0 {
-        return (false, len);
-    }
-    (false, 0)
+    (invert, len)
 }
 
 impl Command<'_> {
-    fn len(&self) -> usize {
+    /// Returns a copy of this command with a different length. Not valid for copy commands.
+    fn with_len(self, len: usize) -> Self {
         match self {
-            Command::Copy(buf) => buf.len(),
-            Command::ByteFill { data: _, len } => *len,
-            Command::WordFill { data: _, len } => *len,
-            Command::Incrementing { start: _, len } => *len,
-            Command::Backreference {
-                src: _,
-                invert: _,
-                len,
-            } => *len,
-            Command::Stop => 0,
-        }
-    }
-
-    fn cost(&self) -> usize {
-        // Includes tweaks to assign higher costs to block types
-        // that are slower to decompress:
-        let args = match self {
-            Command::Copy(buf) => buf.len(),
-            Command::ByteFill { data: _, len: _ } => 1,
-            Command::WordFill { data: _, len: _ } => 2,
-            Command::Incrementing { start: _, len: _ } => 2,
+            Command::ByteFill { data, len: _ } => Command::ByteFill { data, len },
+            Command::WordFill { data, len: _ } => Command::WordFill { data, len },
+            Command::Incrementing { start, len: _ } => Command::Incrementing { start, len },
             Command::Backreference {
-                src: Reference::Relative(_),
-                invert: _,
+                src,
+                invert,
                 len: _,
-            } => 3,
-            Command::Backreference {
-                src: _,
-                invert: _,
-                len: _,
-            } => 4,
-            Command::Stop => 0,
-        };
-
-        if self.len() <= 32 {
-            args + 1
-        } else {
-            args + 2
+            } => Command::Backreference { src, invert, len },
+            Command::Copy(_) | Command::Stop => unreachable!(),
         }
     }
 
@@ -213,7 +1566,7 @@ impl Command<'_> {
             if len < 32 && cmd != 7 {
                 dst.push((cmd << 5) | len as u8);
             } else {
-                assert!(len < Command::MAX_LEN);
+                assert!(len < MAX_COMMAND_LEN);
                 dst.push(0xE0 | (cmd << 2) | (len >> 8) as u8);
                 dst.push(len as u8);
             }
@@ -233,7 +1586,7 @@ impl Command<'_> {
                     Reference::Relative(offset) => {
                         assert_ne!(*offset, 0);
                         if *invert {
-                            assert!(*len <= 0x300);
+                            assert!(*len <= MAX_INVERTED_RELATIVE_LEN);
                         }
                         _write(6 | *invert as u8, *len, &[*offset], dst)
                     }
@@ -0,0 +1,55 @@
+//! A minimal self-describing container for compressed data.
+//!
+//! ROM data is usually located through an external table, but a blob stored on its own (in a file,
+//! say) needs to identify itself. The container is an 8-byte header, followed by the compressed
+//! stream: the magic bytes [`PACK_MAGIC`], then the length of the decompressed data as a
+//! little-endian `u32`.
+
+use alloc::vec::Vec;
+
+use crate::{compress_into, decompress, DecompressionError};
+
+/// The magic bytes at the start of a container written by [`pack`].
+pub const PACK_MAGIC: [u8; 4] = *b"LZNT";
+
+/// The length of the container header: the magic bytes and the decompressed length.
+const HEADER_LEN: usize = PACK_MAGIC.len() + 4;
+
+/// Compresses the provided data and wraps it in a container recording its decompressed length.
+///
+/// # Panics
+///
+/// Panics if `src` is 4 GiB or longer, since its length wouldn't fit in the header.
+pub fn pack(src: &[u8]) -> Vec<u8> {
+    let len = u32::try_from(src.len()).expect("data is too large to pack");
+    let mut dst = Vec::with_capacity(HEADER_LEN);
+    dst.extend_from_slice(&PACK_MAGIC);
+    dst.extend_from_slice(&len.to_le_bytes());
+    compress_into(src, &mut dst);
+    dst
+}
+
+/// Decompresses data wrapped by [`pack`], checking the header.
+///
+/// Fails with [`DecompressionError::BadMagic`] if `data` doesn't start with [`PACK_MAGIC`], and
+/// with [`DecompressionError::LengthMismatch`] if the stream doesn't decompress to the length
+/// recorded in the header. Offsets in other errors are positions in `data`, including the header.
+/// Any data after the end of the stream is ignored.
+pub fn unpack(data: &[u8]) -> Result<Vec<u8>, DecompressionError> {
+    let header = data
+        .strip_prefix(&PACK_MAGIC)
+        .ok_or(DecompressionError::BadMagic)?;
+    let len = header
+        .get(..4)
+        .ok_or(DecompressionError::UnexpectedEof { offset: data.len() })?;
+    let expected = u32::from_le_bytes(len.try_into().unwrap()) as usize;
+
+    let output = decompress(&data[HEADER_LEN..]).map_err(|e| e.shifted(HEADER_LEN))?;
+    if output.len() != expected {
+        return Err(DecompressionError::LengthMismatch {
+            expected,
+            actual: output.len(),
+        });
+    }
+    Ok(output)
+}
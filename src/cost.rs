@@ -0,0 +1,215 @@
+use crate::{commands, validate, Command, CompressMode, DecompressionError, Reference};
+
+/// Assigns a cost to each command, used by the compressor to choose between candidate commands.
+///
+/// The compressor prefers the command with the best ratio of output length to cost, and only
+/// uses a command instead of a literal copy if its cost is at least
+/// [`min_savings`](crate::CompressOptions::min_savings) less than its length. Costs are therefore
+/// expected to be roughly comparable to encoded sizes in bytes, and must be nonzero for every
+/// command other than [`Command::Stop`].
+///
+/// This trait is implemented for closures taking a `&Command`, so a custom model can be written
+/// inline.
+pub trait CostModel {
+    /// Returns the cost of the given command.
+    fn cost(&self, cmd: &Command) -> usize;
+}
+
+/// The default cost model, which favors decompression speed.
+///
+/// Costs are based on encoded size, with tweaks to assign higher costs to command types that are
+/// slower to decompress.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct DefaultCostModel;
+
+impl CostModel for DefaultCostModel {
+    fn cost(&self, cmd: &Command) -> usize {
+        // Includes tweaks to assign higher costs to block types
+        // that are slower to decompress:
+        let args = match cmd {
+            Command::Copy(buf) => buf.len(),
+            Command::ByteFill { data: _, len: _ } => 1,
+            Command::WordFill { data: _, len: _ } => 2,
+            Command::Incrementing { start: _, len: _ } => 2,
+            Command::Backreference {
+                src: Reference::Relative(_),
+                invert: _,
+                len: _,
+            } => 3,
+            Command::Backreference {
+                src: _,
+                invert: _,
+                len: _,
+            } => 4,
+            Command::Stop => 0,
+        };
+
+        if cmd.len() <= 32 {
+            args + 1
+        } else {
+            args + 2
+        }
+    }
+}
+
+/// A cost model that weighs commands purely by their encoded size.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct SizeCostModel;
+
+impl CostModel for SizeCostModel {
+    fn cost(&self, cmd: &Command) -> usize {
+        cmd.encoded_len()
+    }
+}
+
+/// A cost model based on the estimated time to decompress each command on the SNES, for data that
+/// must be decompressed within a tight budget (such as during vblank).
+///
+/// Costs are the cycle estimates from [`cycles`](SnesCycleCost::cycles), scaled so that copying one
+/// literal byte costs 1, which keeps them comparable to encoded sizes as [`CostModel`] expects.
+/// Since the costs already measure decompression time, use a
+/// [`min_savings`](crate::CompressOptions::min_savings) of 1 with this model: the default margin
+/// stands in for decompression time under size-based costs, and only slows decompression down here.
+///
+/// The estimates are 65816 cycle counts for the instructions a decompressor shaped like Super
+/// Metroid's (the routine at $80:B0FF) executes for each part of a command. Each constant lists
+/// its instruction sequence and the cycles of each instruction, taken from the opcode table of the
+/// WDC W65C816S datasheet, assuming native mode with an 8-bit accumulator, 16-bit index registers
+/// and a page-aligned direct page:
+///
+/// - the compressed input is read through a subroutine using a 24-bit pointer at `$47`, which
+///   skips to the next bank when it reaches the end of one;
+/// - output is written through a 24-bit pointer at `$4C` indexed by `Y`, with the remaining length
+///   counted down in `X`;
+/// - backreferences read their source through a second pointer at `$50`, set up so that it is
+///   indexed by the same `Y`.
+///
+/// The sequences model that structure rather than transcribe the game's listing, and every cycle is
+/// counted equally, ignoring memory speed (SlowROM versus FastROM) and the rare path that crosses a
+/// bank. Treat the results as relative weights rather than exact timings.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct SnesCycleCost;
+
+impl SnesCycleCost {
+    /// Reading one byte of compressed input: `JSR abs` (6), `LDA [$47]` (6), `INC $47` (5), a
+    /// taken `BNE` past the bank boundary check (3) and `RTS` (6).
+    const READ: usize = 26;
+    /// Reading and decoding a 1-byte header and dispatching to the command's handler:
+    /// [`READ`](Self::READ) (26); `CMP #$FF` and an untaken `BEQ` to check for a stop (4); `STA dp`
+    /// to keep the header (3); `AND #$E0`, `CMP #$E0` and an untaken `BEQ` to check for an extended
+    /// header (6); four `LSR` and a `TAX` for the type (10); `LDA dp`, `AND #$1F`, `INC A`,
+    /// `STA dp` and `STZ dp` for the length (13); `JMP (abs,X)` (6); and `LDX dp` in the handler
+    /// to load the length (4).
+    const DISPATCH: usize = 72;
+    /// The extra work of decoding a 2-byte header, besides reading its second byte: `LDA dp`,
+    /// three `ASL`, `AND #$E0`, four `LSR` and a `TAX` for the type (21); `LDA dp`, `AND #$03` and
+    /// `XBA` for the high bits of the length (8); `REP #$20`, `INC A`, a 16-bit `STA dp` and
+    /// `SEP #$20` for the length (12); and the `BEQ` being taken (1). This replaces the 23 cycles
+    /// of decoding a 1-byte header.
+    const EXTENDED: usize = 19;
+    /// Writing one byte of output and advancing the loop: `STA [$4C],Y` (6), `INY` (2), `DEX` (2)
+    /// and a taken `BNE` (3).
+    const WRITE: usize = 13;
+    /// Converting a relative distance into a source pointer, after reading it: `STA dp` and
+    /// `STZ dp` to widen it (6), `REP #$20` (3), `LDA $4C`, `SEC`, `SBC dp` and `STA $50` (14),
+    /// and `SEP #$20` (3).
+    const RELATIVE_SETUP: usize = 26;
+    /// Converting an absolute address into a source pointer, after reading each of its bytes:
+    /// `STA dp` twice (6), `REP #$20` (3), `LDA $4C`, `CLC` and `ADC dp` to find the address (10),
+    /// `STY dp`, `SEC` and `SBC dp` to cancel out the `Y` index (10), `STA $50` (4), and
+    /// `SEP #$20` (3).
+    const ABSOLUTE_SETUP: usize = 36;
+
+    /// Returns the approximate number of CPU cycles it takes to decompress `cmd`.
+    pub fn cycles(&self, cmd: &Command) -> usize {
+        // Besides writing, word fills alternate bytes with `XBA` (3), incrementing runs add one with
+        // `INC A` (2), and backreferences read their source with `LDA [$50],Y` (6), inverted ones
+        // then applying `EOR #$FF` (2).
+        let backreference = |invert: bool| Self::WRITE + 6 + 2 * invert as usize;
+        let (operands, setup, per_byte) = match cmd {
+            Command::Copy(_) => (0, 0, Self::READ + Self::WRITE),
+            Command::ByteFill { data: _, len: _ } => (1, 0, Self::WRITE),
+            Command::WordFill { data: _, len: _ } => (2, 0, Self::WRITE + 3),
+            Command::Incrementing { start: _, len: _ } => (1, 0, Self::WRITE + 2),
+            Command::Backreference {
+                src: Reference::Absolute(_),
+                invert,
+                len: _,
+            } => (2, Self::ABSOLUTE_SETUP, backreference(*invert)),
+            Command::Backreference {
+                src: Reference::Relative(_),
+                invert,
+                len: _,
+            } => (1, Self::RELATIVE_SETUP, backreference(*invert)),
+            Command::Stop => return Self::DISPATCH,
+        };
+
+        // A copy's data is read as it's written, so it's counted in `per_byte`
+        let data_len = match cmd {
+            Command::Copy(buf) => buf.len(),
+            _ => operands,
+        };
+        let header = match cmd.encoded_len() - data_len {
+            1 => Self::DISPATCH,
+            _ => Self::DISPATCH + Self::READ + Self::EXTENDED,
+        };
+        header + operands * Self::READ + setup + cmd.len() * per_byte
+    }
+}
+
+impl CostModel for SnesCycleCost {
+    fn cost(&self, cmd: &Command) -> usize {
+        self.cycles(cmd).div_ceil(Self::READ + Self::WRITE)
+    }
+}
+
+impl CostModel for CompressMode {
+    fn cost(&self, cmd: &Command) -> usize {
+        match self {
+            CompressMode::DecodeSpeed => DefaultCostModel.cost(cmd),
+            CompressMode::SizeOptimal => SizeCostModel.cost(cmd),
+        }
+    }
+}
+
+impl<F: Fn(&Command) -> usize> CostModel for F {
+    fn cost(&self, cmd: &Command) -> usize {
+        self(cmd)
+    }
+}
+
+/// Returns the total cost of the commands in a compressed stream according to
+/// [`DefaultCostModel`], as a rough measure of how long the stream takes to decompress.
+///
+/// The default cost model is only a proxy for decompression time, not an exact cycle count, but
+/// it's useful for comparing encodings of the same data: together with
+/// [`decompressed_size`](crate::decompressed_size), a lower cost suggests a faster-decoding stream.
+/// Fails with the same errors as [`validate`] for malformed input.
+pub fn decode_cost(src: &[u8]) -> Result<usize, DecompressionError> {
+    decode_cost_with(src, &DefaultCostModel)
+}
+
+/// Returns the total cost of the commands in a compressed stream according to the given model.
+pub fn decode_cost_with<C: CostModel + ?Sized>(
+    src: &[u8],
+    cost: &C,
+) -> Result<usize, DecompressionError> {
+    validate(src)?;
+    commands(src)
+        .map(|cmd| cmd.map(|cmd| cost.cost(&cmd)))
+        .sum()
+}
+
+/// Returns whether decompressing `src` costs at most `budget` according to the given model.
+///
+/// This is [`decode_cost_with`] compared against a budget, for build-time checks that data
+/// decompresses within a fixed time, such as a vblank. The budget is in the model's units, so to
+/// check against an estimated cycle count, pass a closure calling [`SnesCycleCost::cycles`] as the
+/// model.
+pub fn fits_in_cycles<C: CostModel + ?Sized>(
+    src: &[u8],
+    budget: u64,
+    cost: &C,
+) -> Result<bool, DecompressionError> {
+    Ok(decode_cost_with(src, cost)? as u64 <= budget)
+}
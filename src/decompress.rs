@@ -1,71 +1,947 @@
-use crate::{Command, Reference};
+use crate::crc::Crc32;
+use crate::{Command, OwnedCommand, Reference, MAX_COMMAND_LEN};
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+#[cfg(feature = "thiserror")]
 use thiserror::Error;
 
 /// Decompresses the provided data.
-pub fn decompress(mut src: &[u8]) -> Result<Vec<u8>, DecompressionError> {
+///
+/// A stream consisting of only the terminating `0xFF` decompresses to an empty output. An empty
+/// `src` is not a valid stream, since it has no terminator, and fails with
+/// [`DecompressionError::MissingStop`].
+///
+/// A `0xFF` byte is only a stop command where a command header is expected. Anywhere else, such as
+/// in a fill's data or a copy's literals, it's an ordinary operand. So the format can't detect most
+/// corruption: a damaged header usually just changes how the following bytes are split into
+/// commands, and the stream decodes to the wrong data (often of the wrong length) without an
+/// error. Compare the result against a known length or checksum (see [`decompress_with_crc`]) when
+/// that matters.
+pub fn decompress(src: &[u8]) -> Result<Vec<u8>, DecompressionError> {
     let mut dst = Vec::new();
+    decompress_into(src, &mut dst)?;
+    Ok(dst)
+}
+
+/// Decompresses the provided data, appending the result to `dst`.
+///
+/// Existing contents of `dst` are left untouched and are not visible to the compressed stream:
+/// backreference addresses are resolved relative to the length `dst` had when this function was
+/// called. That is, `Reference::Absolute(0)` refers to the first byte written by this call, not to
+/// `dst[0]`. This makes it possible to reuse one buffer across many calls (with or without
+/// clearing it in between) and get the same result as [`decompress`].
+///
+/// On error, `dst` may contain a partially decompressed result.
+pub fn decompress_into(src: &[u8], dst: &mut Vec<u8>) -> Result<(), DecompressionError> {
+    let base = dst.len();
+    decode(src, dst, base, usize::MAX, false).map(|_| ())
+}
+
+/// Decompresses the provided data, failing with [`DecompressionError::OutputTooLarge`] if the
+/// output would be longer than `max_output` bytes.
+///
+/// A small compressed stream can expand to a very large output, so this should be preferred over
+/// [`decompress`] for untrusted input. The limit is checked before each command is executed, so
+/// no more than `max_output` bytes are ever allocated.
+pub fn decompress_bounded(src: &[u8], max_output: usize) -> Result<Vec<u8>, DecompressionError> {
+    let mut dst = Vec::new();
+    decode(src, &mut dst, 0, max_output, false)?;
+    Ok(dst)
+}
+
+/// Decompresses the provided data, also returning the CRC-32 of the output.
+///
+/// The CRC is the standard one used by zlib and PNG (reflected polynomial `0xEDB88320`), so it can
+/// be compared against checksums computed by other tools. It's updated as each command is
+/// decoded, while its output is still in cache, rather than in a second pass over the output.
+pub fn decompress_with_crc(src: &[u8]) -> Result<(Vec<u8>, u32), DecompressionError> {
+    let mut src = Cursor::new(src);
+    let mut dst = Vec::new();
+    let mut crc = Crc32::new();
     loop {
-        match read_cmd(&mut src)? {
-            Command::Copy(buf) => dst.extend_from_slice(buf),
-            Command::ByteFill { data, len } => dst.extend(std::iter::repeat(data).take(len)),
-            Command::WordFill { data, len } => {
-                dst.extend(std::iter::repeat(data.to_le_bytes()).flatten().take(len))
+        let offset = src.offset();
+        let cmd = read_cmd(&mut src)?;
+        if cmd == Command::Stop {
+            return Ok((dst, crc.finish()));
+        }
+        let start = dst.len();
+        execute(cmd, &mut dst, 0, offset)?;
+        crc.update(&dst[start..]);
+    }
+}
+
+/// Decompresses the provided data into `out`, without allocating, and returns the number of bytes
+/// written.
+///
+/// Fails with [`DecompressionError::OutputBufferFull`] if the output would be longer than `out`.
+/// The check is made before each command is executed, so `out` then holds the output of every
+/// command before the one that didn't fit. Backreferences read from the part of `out` that has
+/// already been written.
+pub fn decompress_into_slice(src: &[u8], out: &mut [u8]) -> Result<usize, DecompressionError> {
+    let mut src = Cursor::new(src);
+    let mut pos = 0;
+    loop {
+        let offset = src.offset();
+        let cmd = read_cmd(&mut src)?;
+        let len = cmd.len();
+        if len > out.len() - pos {
+            return Err(DecompressionError::OutputBufferFull { offset });
+        }
+        let dst = &mut out[pos..pos + len];
+        match cmd {
+            Command::Copy(buf) => dst.copy_from_slice(buf),
+            Command::ByteFill { data, len: _ } => dst.fill(data),
+            Command::WordFill { data, len: _ } => {
+                for (i, x) in dst.iter_mut().enumerate() {
+                    *x = data.to_le_bytes()[i % 2];
+                }
+            }
+            Command::Incrementing { start, len: _ } => {
+                for (i, x) in dst.iter_mut().enumerate() {
+                    *x = start.wrapping_add(i as u8);
+                }
             }
-            Command::Incrementing { start, len } => dst
-                .extend(std::iter::successors(Some(start), |x| Some(x.wrapping_add(1))).take(len)),
             Command::Backreference { src, invert, len } => {
-                let start = match src {
-                    Reference::Absolute(i) => i as usize,
-                    Reference::Relative(i) => {
-                        if (i as usize) <= dst.len() {
-                            dst.len() - i as usize
-                        } else {
-                            return Err(DecompressionError::WindowOutOfRange);
-                        }
-                    }
-                };
-
-                if start >= dst.len() {
-                    return Err(DecompressionError::WindowOutOfRange);
+                let start = backreference_start(src, pos, offset)?;
+                for i in 0..len {
+                    out[pos + i] = out[start + i] ^ if invert { 0xFF } else { 0 };
                 }
+            }
+            Command::Stop => return Ok(pos),
+        }
+        pos += len;
+    }
+}
+
+/// A destination for decompressed data, for decoding into something other than a `Vec<u8>`.
+///
+/// The decompressor writes every byte of output through [`push`](DecodeSink::push) or
+/// [`extend_from_slice`](DecodeSink::extend_from_slice), in order, and reads earlier output back
+/// through [`back`](DecodeSink::back) to execute backreferences.
+pub trait DecodeSink {
+    /// Returns the number of bytes written so far. Backreferences are resolved against this: an
+    /// absolute backreference to address `n` reads the byte that was at position `n` when it was
+    /// written, and a relative one with distance `n` reads the byte `n` positions before the end.
+    fn written(&self) -> usize;
+
+    /// Appends one byte to the output.
+    fn push(&mut self, byte: u8);
 
-                dst.reserve(len);
+    /// Appends several bytes to the output.
+    fn extend_from_slice(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.push(byte);
+        }
+    }
+
+    /// Returns the byte written `distance` positions before the end of the output, so that
+    /// `back(1)` is the last byte written.
+    ///
+    /// `distance` is always between 1 and [`written`](DecodeSink::written), and it must return
+    /// exactly the byte that was written there, or the output will be wrong. A sink that keeps
+    /// only part of the output can serve relative backreferences from the last
+    /// [`MAX_RELATIVE_DISTANCE`](crate::MAX_RELATIVE_DISTANCE) bytes, but absolute backreferences
+    /// may reach back to anywhere in the first 64 KiB.
+    fn back(&self, distance: usize) -> u8;
+}
+
+/// The whole vector is the output, so any existing contents are visible to backreferences (unlike
+/// with [`decompress_into`]).
+impl DecodeSink for Vec<u8> {
+    fn written(&self) -> usize {
+        self.len()
+    }
+
+    fn push(&mut self, byte: u8) {
+        Vec::push(self, byte);
+    }
+
+    fn extend_from_slice(&mut self, bytes: &[u8]) {
+        Vec::extend_from_slice(self, bytes);
+    }
+
+    fn back(&self, distance: usize) -> u8 {
+        self[self.len() - distance]
+    }
+}
+
+/// Decompresses the provided data into `sink`.
+///
+/// This allows writing output straight into its destination, such as a region of a ROM image or a
+/// checksum, without collecting it in a `Vec` first. Returns the number of bytes of `src` consumed.
+/// On error, `sink` may already have received part of the output.
+pub fn decompress_to_sink<S: DecodeSink + ?Sized>(
+    src: &[u8],
+    sink: &mut S,
+) -> Result<usize, DecompressionError> {
+    let mut src = Cursor::new(src);
+    let mut scratch = Vec::new();
+    loop {
+        let offset = src.offset();
+        match read_cmd(&mut src)? {
+            Command::Stop => return Ok(src.offset()),
+            Command::Copy(buf) => sink.extend_from_slice(buf),
+            Command::Backreference { src, invert, len } => {
+                let start = backreference_start(src, sink.written(), offset)?;
                 for i in 0..len {
-                    dst.push(dst[start + i] ^ if invert { 0xFF } else { 0 });
+                    let distance = sink.written() - (start + i);
+                    sink.push(sink.back(distance) ^ if invert { 0xFF } else { 0 });
                 }
             }
+            cmd => {
+                scratch.clear();
+                execute(cmd, &mut scratch, 0, offset)?;
+                sink.extend_from_slice(&scratch);
+            }
+        }
+    }
+}
+
+/// Decompresses the provided data, failing with [`DecompressionError::NonCanonicalEncoding`] if
+/// any command is not encoded the way [`compress`](crate::compress) would encode it.
+///
+/// That is, a command must use the 1-byte header whenever its length fits (up to 32 bytes, except
+/// for inverted relative backreferences, which always need the extended form), and a relative
+/// backreference must not have an offset of zero. This is useful for checking that a tool produces
+/// canonical output; [`canonicalize`](crate::canonicalize) converts a stream to this form.
+pub fn decompress_strict(src: &[u8]) -> Result<Vec<u8>, DecompressionError> {
+    let mut dst = Vec::new();
+    decode(src, &mut dst, 0, usize::MAX, true)?;
+    Ok(dst)
+}
+
+/// Decompresses a stream that is not terminated by a stop command, such as one produced with
+/// [`emit_stop`](crate::CompressOptions::emit_stop) disabled.
+///
+/// Commands are decoded until the end of `src`. A `0xFF` stop command also ends decoding, and
+/// anything after it is ignored, so terminated streams are accepted as well.
+pub fn decompress_unterminated(src: &[u8]) -> Result<Vec<u8>, DecompressionError> {
+    let mut dst = Vec::new();
+    decode_unterminated(src, &mut dst)?;
+    Ok(dst)
+}
+
+/// Decodes commands from `src` into `dst` until the end of `src` or a stop command.
+pub(crate) fn decode_unterminated(src: &[u8], dst: &mut Vec<u8>) -> Result<(), DecompressionError> {
+    let mut src = Cursor::new(src);
+    while !src.remaining().is_empty() {
+        let offset = src.offset();
+        match read_cmd(&mut src)? {
+            Command::Stop => break,
+            cmd => execute(cmd, dst, 0, offset)?,
+        }
+    }
+    Ok(())
+}
+
+/// Decompresses the provided data, returning the output produced before the first error (if any)
+/// along with the error.
+///
+/// This is useful for inspecting corrupted data, to see how far it decodes before going bad. The
+/// command that failed contributes no output. If no error occurs, the result is the same as
+/// [`decompress`].
+pub fn decompress_lenient(src: &[u8]) -> (Vec<u8>, Option<DecompressionError>) {
+    let mut dst = Vec::new();
+    let err = decode(src, &mut dst, 0, usize::MAX, false).err();
+    (dst, err)
+}
 
+/// Decompresses only the first `max_bytes` bytes of output, without decoding the rest of the
+/// stream.
+///
+/// The result is the same as the first `max_bytes` bytes of [`decompress`] (or all of it, if the
+/// output is shorter). Commands after the cutoff are never read, so this succeeds even if the
+/// stream is malformed after that point.
+pub fn decompress_prefix(src: &[u8], max_bytes: usize) -> Result<Vec<u8>, DecompressionError> {
+    let mut src = Cursor::new(src);
+    let mut dst = Vec::new();
+    while dst.len() < max_bytes {
+        let offset = src.offset();
+        match read_cmd(&mut src)? {
             Command::Stop => break,
+            cmd => execute(cmd, &mut dst, 0, offset)?,
         }
     }
+    dst.truncate(max_bytes);
     Ok(dst)
 }
 
+/// Decompresses the provided data, also returning the number of bytes of `src` that were consumed
+/// (up to and including the terminating `0xFF`).
+///
+/// Decoding stops at the `0xFF` terminator and never reads past it, so any data in `src` after
+/// the end of the compressed stream is ignored. Use the consumed length to find it:
+///
+/// ```
+/// let rom = [0x23, 0xAA, 0xFF, 0x00, 0x00, 0x12];
+///
+/// let (data, consumed) = lznint::decompress_with_consumed(&rom)?;
+/// assert_eq!(data, [0xAA, 0xAA, 0xAA, 0xAA]);
+///
+/// let trailing = &rom[consumed..];
+/// assert_eq!(trailing, [0x00, 0x00, 0x12]);
+/// # Ok::<(), lznint::DecompressionError>(())
+/// ```
+pub fn decompress_with_consumed(src: &[u8]) -> Result<(Vec<u8>, usize), DecompressionError> {
+    let mut dst = Vec::new();
+    let consumed = decode(src, &mut dst, 0, usize::MAX, false)?;
+    Ok((dst, consumed))
+}
+
+/// Decompresses a stream that starts at `rom[offset..]`, returning the decompressed data and the
+/// offset just past its terminating `0xFF`.
+///
+/// This is convenient for following a table of pointers into a ROM. Offsets in errors are
+/// positions in `rom`, not relative to `offset`. An `offset` past the end of `rom` fails with
+/// [`DecompressionError::UnexpectedEof`].
+pub fn decompress_at(rom: &[u8], offset: usize) -> Result<(Vec<u8>, usize), DecompressionError> {
+    let src = rom
+        .get(offset..)
+        .ok_or(DecompressionError::UnexpectedEof { offset: rom.len() })?;
+    let mut dst = Vec::new();
+    let consumed = decode(src, &mut dst, 0, usize::MAX, false).map_err(|e| e.shifted(offset))?;
+    Ok((dst, offset + consumed))
+}
+
+/// Decompresses a sequence of streams stored back-to-back, returning the output of each one.
+///
+/// Each stream starts immediately after the terminating `0xFF` of the previous one, and decoding
+/// continues until the input is exhausted. If the input ends partway through a stream, the error
+/// is returned; its offset (relative to the start of `src`) shows how far decoding got.
+pub fn decompress_all(src: &[u8]) -> Result<Vec<Vec<u8>>, DecompressionError> {
+    let mut blocks = Vec::new();
+    let mut offset = 0;
+    while offset < src.len() {
+        let (block, end) = decompress_at(src, offset)?;
+        blocks.push(block);
+        offset = end;
+    }
+    Ok(blocks)
+}
+
+/// Returns the length of the data that `src` decompresses to, without decompressing it.
+///
+/// This only walks the command headers, so it runs in time proportional to the number of commands
+/// rather than the size of the output. It fails with the same errors as [`decompress`] for
+/// malformed input, including backreferences that point outside of the output.
+pub fn decompressed_size(src: &[u8]) -> Result<usize, DecompressionError> {
+    scan(src).map(|(size, _)| size)
+}
+
+/// Returns the number of commands in `src`, not counting the terminating [`Stop`](Command::Stop).
+///
+/// Together with [`decompressed_size`], this gives the average output per command, which is handy
+/// when comparing compressor settings. Like [`decompressed_size`], it fails with the same errors
+/// as [`decompress`] for malformed input.
+pub fn command_count(src: &[u8]) -> Result<usize, DecompressionError> {
+    validate(src)?;
+    Ok(commands(src).count() - 1)
+}
+
+/// Returns the length of the data that `src` decompresses to, which is exactly the largest buffer
+/// needed to decompress it.
+///
+/// This is the same as [`decompressed_size`]. To reject inputs without walking them at all, compare
+/// [`worst_case_output_for_input_len`] against a limit instead.
+pub fn max_output_size(src: &[u8]) -> Result<usize, DecompressionError> {
+    decompressed_size(src)
+}
+
+/// Returns the largest output that any valid `input_len`-byte compressed stream can decode to.
+///
+/// A stream must end with a 1-byte stop command. The densest command is an extended-form
+/// [`ByteFill`](crate::Command::ByteFill), which produces [`MAX_COMMAND_LEN`] bytes from 3 bytes of
+/// input. (An extended backreference produces as much, but from 3 or 4 bytes and only after some
+/// output already exists.) The best use of 2 leftover bytes is a short `ByteFill` of 32 bytes, and
+/// a single leftover byte can't encode any command that produces output.
+///
+/// Saturates at `usize::MAX`.
+pub fn worst_case_output_for_input_len(input_len: usize) -> usize {
+    let available = input_len.saturating_sub(1);
+    let leftover = if available % 3 == 2 { 32 } else { 0 };
+    (available / 3)
+        .saturating_mul(MAX_COMMAND_LEN)
+        .saturating_add(leftover)
+}
+
+/// Checks that `src` is a valid compressed stream, returning its length in bytes (up to and
+/// including the terminating `0xFF`).
+///
+/// Every backreference is checked against the length of the output produced so far, so this
+/// fails exactly when [`decompress`] would, but without producing any output. Any data after the
+/// end of the stream is ignored.
+pub fn validate(src: &[u8]) -> Result<usize, DecompressionError> {
+    scan(src).map(|(_, consumed)| consumed)
+}
+
+/// Returns the length of the compressed stream that starts at `rom[offset..]` (up to and
+/// including the terminating `0xFF`), without decompressing it.
+///
+/// This is [`validate`] applied at an offset, so the stream is fully checked, but no output is
+/// produced and nothing is allocated. As with [`decompress_at`], offsets in errors are positions
+/// in `rom`, and an `offset` past the end of `rom` fails with
+/// [`DecompressionError::UnexpectedEof`].
+pub fn block_length(rom: &[u8], offset: usize) -> Result<usize, DecompressionError> {
+    let src = rom
+        .get(offset..)
+        .ok_or(DecompressionError::UnexpectedEof { offset: rom.len() })?;
+    validate(src).map_err(|e| e.shifted(offset))
+}
+
+/// Walks the commands in `src` without executing them, returning the length of the output and the
+/// number of bytes consumed.
+fn scan(src: &[u8]) -> Result<(usize, usize), DecompressionError> {
+    let mut src = Cursor::new(src);
+    let mut size = 0;
+    loop {
+        let offset = src.offset();
+        match read_cmd(&mut src)? {
+            Command::Stop => return Ok((size, src.offset())),
+            Command::Backreference { src, len, .. } => {
+                backreference_start(src, size, offset)?;
+                size += len;
+            }
+            cmd => size += cmd.len(),
+        }
+    }
+}
+
+/// Returns an iterator over the decompressed bytes of a stream.
+///
+/// Commands are decoded only as more bytes are needed, though all output is retained internally
+/// to resolve backreferences. If an error occurs, it is returned as the last item.
+pub fn decompress_iter(src: &[u8]) -> impl Iterator<Item = Result<u8, DecompressionError>> + '_ {
+    let mut decompressor = Decompressor::new(src);
+    let mut pos = 0;
+    core::iter::from_fn(move || {
+        while pos == decompressor.output().len() {
+            if let Err(e) = decompressor.step()? {
+                return Some(Err(e));
+            }
+        }
+        pos += 1;
+        Some(Ok(decompressor.output()[pos - 1]))
+    })
+}
+
+/// Returns an iterator over the commands in a compressed stream, without decompressing it.
+///
+/// The final [`Command::Stop`] is included. Iteration ends after the `Stop` command or after the
+/// first error.
+pub fn commands(src: &[u8]) -> impl Iterator<Item = Result<Command<'_>, DecompressionError>> {
+    let mut src = Cursor::new(src);
+    let mut done = false;
+    core::iter::from_fn(move || {
+        if done {
+            return None;
+        }
+        let cmd = read_cmd(&mut src);
+        done = !matches!(cmd, Ok(cmd) if cmd != Command::Stop);
+        Some(cmd)
+    })
+}
+
+/// Parses a compressed stream into a list of commands, without decompressing it.
+///
+/// Each command is paired with the offset of its first byte in `src`. The final
+/// [`Command::Stop`] is included.
+pub fn disassemble(src: &[u8]) -> Result<Vec<(usize, OwnedCommand)>, DecompressionError> {
+    let mut result = Vec::new();
+    let mut src = Cursor::new(src);
+    loop {
+        let offset = src.offset();
+        let cmd = read_cmd(&mut src)?;
+        result.push((offset, cmd.into()));
+        if cmd == Command::Stop {
+            return Ok(result);
+        }
+    }
+}
+
+/// Formats a compressed stream as text, one command per line, for debugging.
+///
+/// Each line shows the offset of a command in `src`, its encoded bytes (up to 8 of them, followed
+/// by `...` for longer copies), and its [`Display`](core::fmt::Display) description. The final
+/// [`Command::Stop`] is included. Like [`disassemble`], this only parses the stream, so
+/// backreferences outside of the output are not reported as errors.
+pub fn annotated_hexdump(src: &[u8]) -> Result<String, DecompressionError> {
+    const MAX_BYTES: usize = 8;
+
+    let mut dump = String::new();
+    let mut cursor = Cursor::new(src);
+    loop {
+        let offset = cursor.offset();
+        let cmd = read_cmd(&mut cursor)?;
+        let encoded = &src[offset..cursor.offset()];
+
+        let mut bytes = String::new();
+        for (k, byte) in encoded.iter().take(MAX_BYTES).enumerate() {
+            if k > 0 {
+                bytes.push(' ');
+            }
+            write!(bytes, "{byte:02X}").unwrap();
+        }
+        if encoded.len() > MAX_BYTES {
+            bytes.push_str(" ...");
+        }
+        writeln!(dump, "{offset:06X}  {bytes:<27}  {cmd}").unwrap();
+
+        if cmd == Command::Stop {
+            return Ok(dump);
+        }
+    }
+}
+
+/// Decompresses the provided data, also returning the range of output produced by each command.
+///
+/// Each range is an `(output_start, output_len)` pair. Ranges are in the same order as the commands
+/// in the stream, and include the final [`Command::Stop`] (with a length of zero), so the `n`th
+/// range corresponds to the `n`th entry of [`disassemble`].
+#[allow(clippy::type_complexity)]
+pub fn decode_map(src: &[u8]) -> Result<(Vec<u8>, Vec<(usize, usize)>), DecompressionError> {
+    let mut src = Cursor::new(src);
+    let mut dst = Vec::new();
+    let mut ranges = Vec::new();
+    loop {
+        let offset = src.offset();
+        let start = dst.len();
+        let cmd = read_cmd(&mut src)?;
+        execute(cmd, &mut dst, 0, offset)?;
+        ranges.push((start, dst.len() - start));
+        if cmd == Command::Stop {
+            return Ok((dst, ranges));
+        }
+    }
+}
+
+/// Decompresses the provided data, calling `on_command` with the offset of each command in `src`
+/// and the command itself before it is executed.
+///
+/// Commands borrow from `src`, so they can be kept after the callback returns. The final
+/// [`Command::Stop`] is included. If a command fails (for instance, a backreference out of range),
+/// `on_command` has already been called with it, so the last call shows where decoding stopped.
+pub fn decompress_traced<'a, F: FnMut(usize, &Command<'a>)>(
+    src: &'a [u8],
+    mut on_command: F,
+) -> Result<Vec<u8>, DecompressionError> {
+    let mut src = Cursor::new(src);
+    let mut dst = Vec::new();
+    loop {
+        let offset = src.offset();
+        let cmd = read_cmd(&mut src)?;
+        on_command(offset, &cmd);
+        execute(cmd, &mut dst, 0, offset)?;
+        if cmd == Command::Stop {
+            return Ok(dst);
+        }
+    }
+}
+
+/// Decompresses data that was compressed with
+/// [`compress_with_dictionary`](crate::compress_with_dictionary), using the same dictionary.
+///
+/// This is equivalent to [`decompress_with_prefix`] with the dictionary as the prefix.
+pub fn decompress_with_dictionary(src: &[u8], dict: &[u8]) -> Result<Vec<u8>, DecompressionError> {
+    decompress_with_prefix(src, dict)
+}
+
+/// Decompresses the provided data, treating `prefix` as output that has already been decompressed.
+///
+/// Backreferences are resolved against `prefix ++ output`: `Reference::Absolute(n)` refers to
+/// byte `n` of the concatenation (so addresses below `prefix.len()` point into the prefix), and
+/// `Reference::Relative(n)` refers to the byte `n` positions before the current end of the
+/// concatenation (so it may reach back into the end of the prefix).
+///
+/// Only the newly decompressed data is returned, not the prefix.
+pub fn decompress_with_prefix(src: &[u8], prefix: &[u8]) -> Result<Vec<u8>, DecompressionError> {
+    let mut dst = prefix.to_vec();
+    decode(src, &mut dst, 0, usize::MAX, false)?;
+    Ok(dst.split_off(prefix.len()))
+}
+
+/// A decompressor that decodes one command at a time.
+///
+/// This allows decompression to be interleaved with other work, with a bounded amount of work per
+/// step. All output is retained, since later backreferences may refer to any of it.
+#[derive(Debug)]
+pub struct Decompressor<'a> {
+    src: Cursor<'a>,
+    output: Vec<u8>,
+    done: bool,
+}
+
+impl<'a> Decompressor<'a> {
+    /// Creates a decompressor for `src`.
+    pub fn new(src: &'a [u8]) -> Self {
+        Decompressor {
+            src: Cursor::new(src),
+            output: Vec::new(),
+            done: false,
+        }
+    }
+
+    /// Decodes the next command, returning the output it produced.
+    ///
+    /// Returns `None` once the end of the stream has been reached, or after an error has been
+    /// returned.
+    pub fn step(&mut self) -> Option<Result<&[u8], DecompressionError>> {
+        if self.done {
+            return None;
+        }
+        let offset = self.src.offset();
+        let start = self.output.len();
+        let result = read_cmd(&mut self.src).and_then(|cmd| {
+            self.done = cmd == Command::Stop;
+            execute(cmd, &mut self.output, 0, offset)
+        });
+        match result {
+            Ok(()) if self.done => None,
+            Ok(()) => Some(Ok(&self.output[start..])),
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+
+    /// Returns `true` if the end of the stream has been reached or an error has occurred.
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+
+    /// Returns the output decoded so far.
+    pub fn output(&self) -> &[u8] {
+        &self.output
+    }
+
+    /// Returns the number of bytes of the compressed stream consumed so far.
+    pub fn consumed(&self) -> usize {
+        self.src.offset()
+    }
+
+    /// Consumes the decompressor, returning the output decoded so far.
+    pub fn into_output(self) -> Vec<u8> {
+        self.output
+    }
+}
+
+/// Decodes `src` into `dst`, returning the number of bytes consumed. Backreferences are resolved
+/// against `dst[base..]`.
+///
+/// Fails if more than `max_output` bytes would be appended to `dst`, or if `strict` is set and a
+/// command is not encoded canonically.
+fn decode(
+    src: &[u8],
+    dst: &mut Vec<u8>,
+    base: usize,
+    max_output: usize,
+    strict: bool,
+) -> Result<usize, DecompressionError> {
+    let mut src = Cursor::new(src);
+    let start = dst.len();
+    loop {
+        let offset = src.offset();
+        match read_cmd_with(&mut src, strict)? {
+            Command::Stop => return Ok(src.offset()),
+            cmd => {
+                if cmd.len() > max_output - (dst.len() - start) {
+                    return Err(DecompressionError::OutputTooLarge { offset });
+                }
+                execute(cmd, dst, base, offset)?
+            }
+        }
+    }
+}
+
+/// Appends the output of a single command to `dst`, resolving backreferences against `dst[base..]`.
+///
+/// `offset` is the position of the command in the compressed stream, used for error reporting.
+pub(crate) fn execute(
+    cmd: Command,
+    dst: &mut Vec<u8>,
+    base: usize,
+    offset: usize,
+) -> Result<(), DecompressionError> {
+    match cmd {
+        Command::Copy(buf) => dst.extend_from_slice(buf),
+        Command::ByteFill { data, len } => dst.extend(core::iter::repeat_n(data, len)),
+        Command::WordFill { data, len } => {
+            dst.extend(core::iter::repeat(data.to_le_bytes()).flatten().take(len))
+        }
+        Command::Incrementing { start, len } => {
+            dst.extend(core::iter::successors(Some(start), |x| Some(x.wrapping_add(1))).take(len))
+        }
+        Command::Backreference { src, invert, len } => {
+            let start = base + backreference_start(src, dst.len() - base, offset)?;
+            dst.reserve(len);
+            for i in 0..len {
+                dst.push(dst[start + i] ^ if invert { 0xFF } else { 0 });
+            }
+        }
+
+        Command::Stop => {}
+    }
+    Ok(())
+}
+
+/// Returns the position in the output that a backreference starts copying from, given the length
+/// of the output so far.
+///
+/// `offset` is the position of the command in the compressed stream, used for error reporting.
+pub(crate) fn backreference_start(
+    src: Reference,
+    output_len: usize,
+    offset: usize,
+) -> Result<usize, DecompressionError> {
+    match src {
+        Reference::Absolute(start) => {
+            let start = start as usize;
+            if start < output_len {
+                Ok(start)
+            } else {
+                Err(DecompressionError::AbsoluteWindowOutOfRange {
+                    offset,
+                    start,
+                    output_len,
+                })
+            }
+        }
+        Reference::Relative(0) => Err(DecompressionError::RelativeZeroOffset { offset }),
+        Reference::Relative(distance) => {
+            let distance = distance as usize;
+            if distance <= output_len {
+                Ok(output_len - distance)
+            } else {
+                Err(DecompressionError::RelativeWindowOutOfRange {
+                    offset,
+                    distance,
+                    output_len,
+                })
+            }
+        }
+    }
+}
+
 /// Errors that can occur during decompression.
-#[derive(Error, Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "thiserror", derive(Error))]
 pub enum DecompressionError {
-    #[error("Unexpected end of input")]
-    UnexpectedEof,
+    /// The input ended in the middle of a command. `offset` is where more data was expected.
+    #[cfg_attr(
+        feature = "thiserror",
+        error("Unexpected end of input at offset {offset}")
+    )]
+    UnexpectedEof { offset: usize },
+
+    /// An absolute backreference started at or after the end of the output decompressed so far.
+    /// `offset` is the position of the backreference command.
+    #[cfg_attr(
+        feature = "thiserror",
+        error(
+            "Absolute backreference at offset {offset} starts at {start}, but the output is only \
+         {output_len} bytes long"
+        )
+    )]
+    AbsoluteWindowOutOfRange {
+        offset: usize,
+        start: usize,
+        output_len: usize,
+    },
+
+    /// A relative backreference reached back before the start of the output. `offset` is the
+    /// position of the backreference command.
+    #[cfg_attr(feature = "thiserror", error(
+        "Relative backreference at offset {offset} reaches back {distance} bytes, but the output \
+         is only {output_len} bytes long"
+    ))]
+    RelativeWindowOutOfRange {
+        offset: usize,
+        distance: usize,
+        output_len: usize,
+    },
+
+    /// A relative backreference had a distance of zero, which would copy from the byte being
+    /// written. `offset` is the position of the backreference command.
+    #[cfg_attr(
+        feature = "thiserror",
+        error("Relative backreference at offset {offset} has a distance of zero")
+    )]
+    RelativeZeroOffset { offset: usize },
+
+    /// The input ended where a command or stop byte was expected. `offset` is the length of the
+    /// input.
+    #[cfg_attr(
+        feature = "thiserror",
+        error("Input ended without a stop command at offset {offset}")
+    )]
+    MissingStop { offset: usize },
+
+    /// The output would exceed the maximum allowed size. `offset` is the position of the command
+    /// that would have exceeded the limit.
+    #[cfg_attr(feature = "thiserror", error("Output too large at offset {offset}"))]
+    OutputTooLarge { offset: usize },
+
+    /// The output did not fit in the buffer passed to [`decompress_into_slice`]. `offset` is the
+    /// position of the command that did not fit.
+    #[cfg_attr(feature = "thiserror", error("Output buffer full at offset {offset}"))]
+    OutputBufferFull { offset: usize },
+
+    /// A command was not encoded in its canonical form (only reported by
+    /// [`decompress_strict`]). `offset` is the position of the command.
+    #[cfg_attr(
+        feature = "thiserror",
+        error("Non-canonical encoding for command at offset {offset}")
+    )]
+    NonCanonicalEncoding { offset: usize },
+
+    /// Rebasing an absolute backreference (with [`rebase_absolute`](crate::rebase_absolute))
+    /// would move it outside of the 16-bit address space. `offset` is the position of the
+    /// backreference command, and `address` is the address it would have been moved to.
+    #[cfg_attr(
+        feature = "thiserror",
+        error("Absolute backreference at offset {offset} can't be rebased to address {address}")
+    )]
+    RebaseOutOfRange { offset: usize, address: i64 },
+
+    /// The data passed to [`unpack`](crate::unpack) doesn't start with
+    /// [`PACK_MAGIC`](crate::PACK_MAGIC).
+    #[cfg_attr(feature = "thiserror", error("Missing container magic bytes"))]
+    BadMagic,
+
+    /// The stream in a container decompressed to a different length than its header records.
+    #[cfg_attr(
+        feature = "thiserror",
+        error("Expected {expected} bytes of decompressed data, but got {actual}")
+    )]
+    LengthMismatch { expected: usize, actual: usize },
+}
 
-    #[error("Window start invalid")]
-    WindowOutOfRange,
+#[cfg(not(feature = "thiserror"))]
+impl core::fmt::Display for DecompressionError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            DecompressionError::UnexpectedEof { offset } => {
+                write!(f, "Unexpected end of input at offset {offset}")
+            }
+            DecompressionError::AbsoluteWindowOutOfRange {
+                offset,
+                start,
+                output_len,
+            } => write!(
+                f,
+                "Absolute backreference at offset {offset} starts at {start}, but the output is \
+                 only {output_len} bytes long"
+            ),
+            DecompressionError::RelativeWindowOutOfRange {
+                offset,
+                distance,
+                output_len,
+            } => write!(
+                f,
+                "Relative backreference at offset {offset} reaches back {distance} bytes, but the \
+                 output is only {output_len} bytes long"
+            ),
+            DecompressionError::RelativeZeroOffset { offset } => write!(
+                f,
+                "Relative backreference at offset {offset} has a distance of zero"
+            ),
+            DecompressionError::MissingStop { offset } => {
+                write!(f, "Input ended without a stop command at offset {offset}")
+            }
+            DecompressionError::OutputTooLarge { offset } => {
+                write!(f, "Output too large at offset {offset}")
+            }
+            DecompressionError::OutputBufferFull { offset } => {
+                write!(f, "Output buffer full at offset {offset}")
+            }
+            DecompressionError::NonCanonicalEncoding { offset } => {
+                write!(f, "Non-canonical encoding for command at offset {offset}")
+            }
+            DecompressionError::RebaseOutOfRange { offset, address } => write!(
+                f,
+                "Absolute backreference at offset {offset} can't be rebased to address {address}"
+            ),
+            DecompressionError::BadMagic => write!(f, "Missing container magic bytes"),
+            DecompressionError::LengthMismatch { expected, actual } => write!(
+                f,
+                "Expected {expected} bytes of decompressed data, but got {actual}"
+            ),
+        }
+    }
+}
+
+#[cfg(not(feature = "thiserror"))]
+impl core::error::Error for DecompressionError {}
+
+impl DecompressionError {
+    /// Adds `by` to the offset in the error, if it has one.
+    pub(crate) fn shifted(mut self, by: usize) -> Self {
+        match &mut self {
+            DecompressionError::UnexpectedEof { offset }
+            | DecompressionError::AbsoluteWindowOutOfRange { offset, .. }
+            | DecompressionError::RelativeWindowOutOfRange { offset, .. }
+            | DecompressionError::RelativeZeroOffset { offset }
+            | DecompressionError::MissingStop { offset }
+            | DecompressionError::OutputTooLarge { offset }
+            | DecompressionError::OutputBufferFull { offset }
+            | DecompressionError::NonCanonicalEncoding { offset }
+            | DecompressionError::RebaseOutOfRange { offset, .. } => *offset += by,
+            DecompressionError::BadMagic | DecompressionError::LengthMismatch { .. } => {}
+        }
+        self
+    }
+}
+
+/// A read position within a compressed stream.
+#[derive(Debug)]
+pub(crate) struct Cursor<'a> {
+    data: &'a [u8],
+    pos: usize,
 }
 
-fn read_byte(src: &mut &[u8]) -> Result<u8, DecompressionError> {
-    if !src.is_empty() {
-        let result = src[0];
-        *src = &src[1..];
+impl<'a> Cursor<'a> {
+    pub(crate) fn new(data: &'a [u8]) -> Self {
+        Cursor { data, pos: 0 }
+    }
+
+    /// Returns the number of bytes read so far.
+    pub(crate) fn offset(&self) -> usize {
+        self.pos
+    }
+
+    fn remaining(&self) -> &'a [u8] {
+        &self.data[self.pos..]
+    }
+}
+
+fn read_byte(src: &mut Cursor) -> Result<u8, DecompressionError> {
+    if let Some(&result) = src.remaining().first() {
+        src.pos += 1;
         Ok(result)
     } else {
-        Err(DecompressionError::UnexpectedEof)
+        Err(DecompressionError::UnexpectedEof { offset: src.pos })
     }
 }
 
-fn read_word(src: &mut &[u8]) -> Result<u16, DecompressionError> {
+fn read_word(src: &mut Cursor) -> Result<u16, DecompressionError> {
     Ok(u16::from_le_bytes([read_byte(src)?, read_byte(src)?]))
 }
 
-fn read_cmd<'a>(src: &mut &'a [u8]) -> Result<Command<'a>, DecompressionError> {
+pub(crate) fn read_cmd<'a>(src: &mut Cursor<'a>) -> Result<Command<'a>, DecompressionError> {
+    read_cmd_with(src, false)
+}
+
+/// Reads a command, also checking that it is encoded canonically if `strict` is set.
+fn read_cmd_with<'a>(
+    src: &mut Cursor<'a>,
+    strict: bool,
+) -> Result<Command<'a>, DecompressionError> {
+    let offset = src.pos;
+    if src.remaining().is_empty() {
+        return Err(DecompressionError::MissingStop { offset: src.pos });
+    }
+
     let cmd = read_byte(src)?;
     if cmd == 0xFF {
         return Ok(Command::Stop);
@@ -75,7 +951,8 @@ fn read_cmd<'a>(src: &mut &'a [u8]) -> Result<Command<'a>, DecompressionError> {
     let mut cmd = cmd >> 5;
 
     // Parse extended size
-    if cmd == 0x7 {
+    let extended = cmd == 0x7;
+    if extended {
         cmd = (len >> 2) as u8;
 
         let next = read_byte(src)?;
@@ -83,14 +960,19 @@ fn read_cmd<'a>(src: &mut &'a [u8]) -> Result<Command<'a>, DecompressionError> {
     }
 
     let len = len + 1;
+    if strict && extended && len <= 32 && cmd != 0x7 {
+        return Err(DecompressionError::NonCanonicalEncoding { offset });
+    }
 
     match cmd {
         0x0 => {
-            if len > src.len() {
-                Err(DecompressionError::UnexpectedEof)
+            if len > src.remaining().len() {
+                Err(DecompressionError::UnexpectedEof {
+                    offset: src.data.len(),
+                })
             } else {
-                let (data, next) = src.split_at(len);
-                *src = next;
+                let data = &src.remaining()[..len];
+                src.pos += len;
                 Ok(Command::Copy(data))
             }
         }
@@ -112,6 +994,9 @@ fn read_cmd<'a>(src: &mut &'a [u8]) -> Result<Command<'a>, DecompressionError> {
             } else {
                 Reference::Relative(read_byte(src)?)
             };
+            if strict && src == Reference::Relative(0) {
+                return Err(DecompressionError::NonCanonicalEncoding { offset });
+            }
             let invert = (cmd & 0x1) != 0;
             Ok(Command::Backreference { src, invert, len })
         }
@@ -0,0 +1,94 @@
+//! Entry points for fuzz targets.
+//!
+//! Each function panics if it finds a bug, so a fuzz target only needs to call it:
+//!
+//! ```ignore
+//! #![no_main]
+//! libfuzzer_sys::fuzz_target!(|input: lznint::fuzz::RoundtripInput| {
+//!     lznint::fuzz::fuzz_roundtrip_with(&input);
+//! });
+//! ```
+
+use alloc::vec::Vec;
+use arbitrary::Arbitrary;
+
+use crate::{
+    commands, compress, compress_bound, compress_with, decompress, decompressed_size, validate,
+    CommandSet, CompressMode, CompressOptions, MAX_COMMAND_LEN,
+};
+
+/// Input for [`fuzz_roundtrip_with`]: data to compress, along with the options to compress it with.
+#[derive(Debug, Clone, Arbitrary)]
+pub struct RoundtripInput {
+    pub data: Vec<u8>,
+    pub mode: CompressMode,
+    pub min_savings: u8,
+    pub fill_early_return: bool,
+    pub prefer_word_fill: bool,
+    pub max_chain: u8,
+    pub max_distance: u16,
+    pub lazy: bool,
+    pub peephole: bool,
+    pub relative_only: bool,
+    pub allowed_commands: CommandSet,
+    pub max_command_len: u16,
+}
+
+impl RoundtripInput {
+    /// Returns the compression options described by this input.
+    pub fn options(&self) -> CompressOptions {
+        CompressOptions {
+            mode: self.mode,
+            min_savings: self.min_savings as usize,
+            fill_early_return: self.fill_early_return,
+            prefer_word_fill: self.prefer_word_fill,
+            // Keep small chain limits likely, since they take different paths than the default.
+            max_chain: if self.max_chain == u8::MAX {
+                usize::MAX
+            } else {
+                self.max_chain as usize
+            },
+            max_distance: if self.max_distance == u16::MAX {
+                usize::MAX
+            } else {
+                self.max_distance as usize
+            },
+            lazy: self.lazy,
+            verify: false,
+            peephole: self.peephole,
+            emit_stop: true,
+            relative_only: self.relative_only,
+            allowed_commands: self.allowed_commands,
+            max_command_len: self.max_command_len as usize % MAX_COMMAND_LEN + 1,
+        }
+    }
+}
+
+/// Compresses `data` with the default options, and checks that it decompresses back to `data`
+/// and fits within [`compress_bound`].
+pub fn fuzz_roundtrip(data: &[u8]) {
+    let compressed = compress(data);
+    assert!(compressed.len() <= compress_bound(data.len()));
+    assert_eq!(decompress(&compressed).unwrap(), data);
+}
+
+/// Compresses the input data with the input's options, and checks that it decompresses back to
+/// the input data.
+pub fn fuzz_roundtrip_with(input: &RoundtripInput) {
+    let opts = input.options();
+    let compressed = compress_with(&input.data, &opts);
+    assert_eq!(decompress(&compressed).unwrap(), input.data);
+    assert!(commands(&compressed).all(|cmd| cmd.unwrap().len() <= opts.max_command_len));
+}
+
+/// Decompresses arbitrary data, checking that this doesn't panic and that the functions that
+/// inspect a stream without decompressing it agree with [`decompress`].
+pub fn fuzz_decompress(data: &[u8]) {
+    let result = decompress(data);
+    match (&result, decompressed_size(data)) {
+        (Ok(output), Ok(size)) => assert_eq!(output.len(), size),
+        (Err(e), Err(size_err)) => assert_eq!(*e, size_err),
+        (result, size) => panic!("decompress returned {result:?}, decompressed_size {size:?}"),
+    }
+    assert_eq!(validate(data).is_ok(), result.is_ok());
+}
@@ -0,0 +1,315 @@
+use alloc::vec::Vec;
+use std::io::{self, Read, Write};
+
+use crate::compress::compress_into;
+use crate::decompress::{backreference_start, execute, read_cmd, Cursor, Decompressor};
+use crate::{
+    decompress_with_consumed, Command, DecompressionError, MAX_ABSOLUTE_OFFSET, MAX_COMMAND_LEN,
+    MAX_RELATIVE_DISTANCE,
+};
+
+/// A [`Read`] adapter that decompresses a stream incrementally.
+///
+/// Commands are decoded only as the caller asks for more data. Decompressed output is retained
+/// internally, since a backreference may point anywhere in the first 64 KiB of output.
+///
+/// Decompression errors are reported as [`io::ErrorKind::InvalidData`]; once an error has been
+/// returned, the reader does not produce any more data.
+#[derive(Debug)]
+pub struct LznintReader<'a> {
+    decompressor: Decompressor<'a>,
+    pos: usize,
+}
+
+impl<'a> LznintReader<'a> {
+    /// Creates a reader that decompresses `src`.
+    pub fn new(src: &'a [u8]) -> Self {
+        LznintReader {
+            decompressor: Decompressor::new(src),
+            pos: 0,
+        }
+    }
+}
+
+impl Read for LznintReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.decompressor.output().len() {
+            match self.decompressor.step() {
+                Some(Ok(_)) => {}
+                Some(Err(e)) => return Err(e.into()),
+                None => break,
+            }
+        }
+
+        let available = &self.decompressor.output()[self.pos..];
+        let len = core::cmp::min(available.len(), buf.len());
+        buf[..len].copy_from_slice(&available[..len]);
+        self.pos += len;
+        Ok(len)
+    }
+}
+
+/// An error from decompressing data read from an I/O stream.
+///
+/// [`DecompressionError`] implements [`PartialEq`] and [`Eq`], which [`io::Error`] does not, so
+/// errors from the underlying stream are kept in this separate type. Both kinds of error convert
+/// into it, so streaming code can use `?` on either. It also converts into an [`io::Error`], with
+/// decompression errors reported as [`io::ErrorKind::InvalidData`].
+///
+/// Compression cannot fail on valid input, so the writer side ([`LznintWriter`]) reports plain
+/// [`io::Error`]s.
+#[derive(Debug)]
+pub enum StreamError {
+    /// The data read from the stream was not valid compressed data.
+    Decompression(DecompressionError),
+
+    /// The underlying stream returned an error.
+    Io(io::Error),
+}
+
+impl core::fmt::Display for StreamError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            StreamError::Decompression(e) => e.fmt(f),
+            StreamError::Io(e) => write!(f, "I/O error: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for StreamError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            StreamError::Decompression(e) => e.source(),
+            StreamError::Io(e) => Some(e),
+        }
+    }
+}
+
+impl From<DecompressionError> for StreamError {
+    fn from(e: DecompressionError) -> Self {
+        StreamError::Decompression(e)
+    }
+}
+
+impl From<io::Error> for StreamError {
+    fn from(e: io::Error) -> Self {
+        StreamError::Io(e)
+    }
+}
+
+impl From<StreamError> for io::Error {
+    fn from(e: StreamError) -> Self {
+        match e {
+            StreamError::Decompression(e) => e.into(),
+            StreamError::Io(e) => e,
+        }
+    }
+}
+
+/// Reads a compressed stream from `reader` and decompresses it.
+///
+/// Data is read until the end of the stream, so anything after the terminating `0xFF` is read but
+/// ignored. Returns the decompressed data and the number of compressed bytes consumed.
+pub fn decompress_from_reader<R: Read>(mut reader: R) -> Result<(Vec<u8>, usize), StreamError> {
+    let mut src = Vec::new();
+    reader.read_to_end(&mut src)?;
+    Ok(decompress_with_consumed(&src)?)
+}
+
+/// Reads a compressed stream from `reader` and decompresses it, discarding anything after the
+/// terminating `0xFF`.
+///
+/// This is [`decompress_from_reader`] without the consumed length. Errors from `reader` are
+/// returned as [`StreamError::Io`], since a [`DecompressionError`] can't hold them.
+pub fn decompress_reader<R: Read>(reader: R) -> Result<Vec<u8>, StreamError> {
+    decompress_from_reader(reader).map(|(data, _)| data)
+}
+
+/// Reads all data from `reader` and compresses it with the default options.
+///
+/// The whole input is buffered in memory, since the compressor needs to see all of it to search
+/// for backreferences.
+pub fn compress_reader<R: Read>(mut reader: R) -> io::Result<Vec<u8>> {
+    let mut src = Vec::new();
+    reader.read_to_end(&mut src)?;
+    let mut dst = Vec::new();
+    compress_into(&src, &mut dst);
+    Ok(dst)
+}
+
+/// Decompresses the provided data, writing the output to `out` as it is produced.
+///
+/// Unlike [`LznintReader`], this does not retain the whole output. An absolute backreference can
+/// only address the first 64 KiB of output and a relative one only the last 255 bytes, so only
+/// those are kept in memory; everything in between is written to `out` and then discarded. This
+/// makes it possible to decode outputs of many megabytes in bounded memory.
+///
+/// Output is written in chunks, so `out` does not need to be buffered. It is not flushed.
+///
+/// Returns the total number of bytes written. Decompression errors are reported as
+/// [`io::ErrorKind::InvalidData`]; output produced before the error may already have been written.
+pub fn decompress_to_writer<W: Write>(src: &[u8], mut out: W) -> io::Result<u64> {
+    let mut src = Cursor::new(src);
+    let mut history = History::new();
+    let mut scratch = Vec::new();
+    loop {
+        let offset = src.offset();
+        match read_cmd(&mut src)? {
+            Command::Stop => break,
+            Command::Backreference { src, invert, len } => {
+                let start = backreference_start(src, history.len(), offset)?;
+                for i in 0..len {
+                    history.push(history.get(start + i) ^ if invert { 0xFF } else { 0 });
+                }
+            }
+            cmd => {
+                scratch.clear();
+                execute(cmd, &mut scratch, 0, offset)?;
+                history.extend(&scratch);
+            }
+        }
+
+        if history.unwritten() >= History::FLUSH_THRESHOLD {
+            history.write_to(&mut out)?;
+        }
+    }
+
+    history.write_to(&mut out)?;
+    Ok(history.len() as u64)
+}
+
+/// The part of the output of [`decompress_to_writer`] that backreferences can still address.
+#[derive(Debug)]
+struct History {
+    /// The beginning of the output, up to [`History::PINNED`] bytes.
+    head: Vec<u8>,
+    /// The most recent output after `head`, starting at position `tail_start`.
+    tail: Vec<u8>,
+    tail_start: usize,
+    /// The number of bytes that have been written out.
+    written: usize,
+}
+
+impl History {
+    /// The number of bytes at the start of the output that must be kept. An absolute
+    /// backreference can start anywhere in the first 64 KiB and run for a full command length.
+    const PINNED: usize = MAX_ABSOLUTE_OFFSET + 1 + MAX_COMMAND_LEN;
+    /// The number of bytes at the end of the output that must be kept for relative backreferences.
+    const WINDOW: usize = MAX_RELATIVE_DISTANCE + 1;
+    /// How much output to accumulate before writing it out.
+    const FLUSH_THRESHOLD: usize = 0x4000;
+
+    fn new() -> Self {
+        History {
+            head: Vec::new(),
+            tail: Vec::new(),
+            tail_start: Self::PINNED,
+            written: 0,
+        }
+    }
+
+    /// Returns the total number of bytes of output produced so far.
+    fn len(&self) -> usize {
+        if self.head.len() < Self::PINNED {
+            self.head.len()
+        } else {
+            self.tail_start + self.tail.len()
+        }
+    }
+
+    fn unwritten(&self) -> usize {
+        self.len() - self.written
+    }
+
+    fn get(&self, pos: usize) -> u8 {
+        if pos < self.head.len() {
+            self.head[pos]
+        } else {
+            self.tail[pos - self.tail_start]
+        }
+    }
+
+    fn push(&mut self, byte: u8) {
+        if self.head.len() < Self::PINNED {
+            self.head.push(byte);
+        } else {
+            self.tail.push(byte);
+        }
+    }
+
+    fn extend(&mut self, data: &[u8]) {
+        let split = core::cmp::min(Self::PINNED - self.head.len(), data.len());
+        self.head.extend_from_slice(&data[..split]);
+        self.tail.extend_from_slice(&data[split..]);
+    }
+
+    /// Writes all unwritten output to `out`, then discards the parts of the tail that can no
+    /// longer be referenced.
+    fn write_to<W: Write>(&mut self, out: &mut W) -> io::Result<()> {
+        if self.written < self.head.len() {
+            out.write_all(&self.head[self.written..])?;
+        }
+        let tail_written = self.written.saturating_sub(self.tail_start);
+        out.write_all(&self.tail[tail_written..])?;
+        self.written = self.len();
+
+        let discard = self.tail.len().saturating_sub(Self::WINDOW);
+        self.tail.drain(..discard);
+        self.tail_start += discard;
+        Ok(())
+    }
+}
+
+/// A [`Write`] adapter that compresses everything written to it.
+///
+/// The compressor needs to see the entire input to search for backreferences, so all data written
+/// is buffered in memory until [`finish`](LznintWriter::finish) is called, which compresses it and
+/// writes the complete stream (including the terminating `0xFF`) to the inner writer. Memory use is
+/// therefore proportional to the input size, plus the compressed output.
+///
+/// Dropping the writer without calling `finish` discards the buffered data.
+#[derive(Debug)]
+pub struct LznintWriter<W: Write> {
+    inner: W,
+    buf: Vec<u8>,
+}
+
+impl<W: Write> LznintWriter<W> {
+    /// Creates a writer that emits compressed data to `inner`.
+    pub fn new(inner: W) -> Self {
+        LznintWriter {
+            inner,
+            buf: Vec::new(),
+        }
+    }
+
+    /// Compresses the buffered data, writes it to the inner writer and flushes it.
+    ///
+    /// Returns the inner writer on success.
+    pub fn finish(mut self) -> io::Result<W> {
+        let mut compressed = Vec::new();
+        compress_into(&self.buf, &mut compressed);
+        self.inner.write_all(&compressed)?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for LznintWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    /// Flushes the inner writer. Buffered data is not compressed until
+    /// [`finish`](LznintWriter::finish) is called.
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl From<DecompressionError> for io::Error {
+    fn from(e: DecompressionError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    }
+}
@@ -11,49 +11,304 @@
 //! let decompressed = lznint::decompress(&compressed).expect("Decompressino failed");
 //! assert_eq!(&decompressed, &input);
 //! ```
+//!
+//! ## Features
+//!
+//! - `std` (enabled by default): enables the `std::io` adapters and [`decompress_to_writer`].
+//!   Without it, the crate is `no_std` and only depends on `alloc`.
+//! - `thiserror` (enabled by default): derives the error types' `Display` and `Error` impls with
+//!   `thiserror`. Without it, equivalent impls are written by hand, with the same messages, so the
+//!   crate has no required dependencies.
+//! - `serde`: implements `Serialize` and `Deserialize` for [`OwnedCommand`] and [`Reference`], so
+//!   that a [`disassemble`]d stream can be edited as text and [`assemble`]d again.
+//! - `arbitrary`: enables the `fuzz` module, with entry points for fuzz targets and
+//!   `Arbitrary` impls for their inputs. Implies `std`.
+//! - `rayon`: compresses blocks in parallel in [`compress_many`]. Implies `std`.
+//! - `testing`: enables the `testing` module, with assertions for checking the codec's invariants
+//!   on your own data.
+
+#![no_std]
+
+extern crate alloc;
+#[cfg(any(feature = "std", test))]
+extern crate std;
 
 mod compress;
+mod container;
+mod cost;
+mod crc;
 mod decompress;
+#[cfg(feature = "arbitrary")]
+pub mod fuzz;
+#[cfg(feature = "std")]
+mod io;
+mod matcher;
+pub mod preprocess;
+#[cfg(any(feature = "testing", test))]
+pub mod testing;
+
+pub use compress::{
+    assemble, canonicalize, compress, compress_best, compress_bound, compress_checked,
+    compress_if_smaller, compress_into, compress_into_chunks, compress_into_chunks_at,
+    compress_many, compress_optimal, compress_or_store, compress_reporting, compress_with,
+    compress_with_cost_model, compress_with_dictionary, compress_with_mode, compress_with_stats,
+    compress_within, compression_ratio, rebase_absolute, try_compress, CommandSet, CommandStats,
+    CompressError, CompressMode, CompressOptions, CompressStats, Compressor,
+};
+pub use container::{pack, unpack, PACK_MAGIC};
+pub use cost::{
+    decode_cost, decode_cost_with, fits_in_cycles, CostModel, DefaultCostModel, SizeCostModel,
+    SnesCycleCost,
+};
+pub use decompress::{
+    annotated_hexdump, block_length, command_count, commands, decode_map, decompress,
+    decompress_all, decompress_at, decompress_bounded, decompress_into, decompress_into_slice,
+    decompress_iter, decompress_lenient, decompress_prefix, decompress_strict, decompress_to_sink,
+    decompress_traced, decompress_unterminated, decompress_with_consumed, decompress_with_crc,
+    decompress_with_dictionary, decompress_with_prefix, decompressed_size, disassemble,
+    max_output_size, validate, worst_case_output_for_input_len, DecodeSink, DecompressionError,
+    Decompressor,
+};
+#[cfg(feature = "std")]
+pub use io::{
+    compress_reader, decompress_from_reader, decompress_reader, decompress_to_writer, LznintReader,
+    LznintWriter, StreamError,
+};
+
+use alloc::vec::Vec;
+use core::fmt;
+
+/// The maximum number of bytes a single command can append to the output.
+pub const MAX_COMMAND_LEN: usize = 0x400;
+
+/// The maximum length of an inverted relative backreference. Longer ones can't be encoded, since
+/// their first byte would be `0xFF` (the stop command).
+pub const MAX_INVERTED_RELATIVE_LEN: usize = 0x300;
 
-pub use compress::compress;
-pub use decompress::{decompress, DecompressionError};
+/// The largest output position an absolute backreference can start at.
+pub const MAX_ABSOLUTE_OFFSET: usize = u16::MAX as usize;
 
-#[derive(Debug)]
-enum Command<'a> {
+/// The largest distance a relative backreference can reach back from the end of the output.
+pub const MAX_RELATIVE_DISTANCE: usize = u8::MAX as usize;
+
+/// The largest input [`try_compress`] accepts: every byte of it can be the source of an absolute
+/// backreference.
+pub const MAX_INPUT_LEN: usize = MAX_ABSOLUTE_OFFSET + 1;
+
+/// A single command in a compressed stream.
+///
+/// Every command other than [`Stop`](Command::Stop) appends `len` bytes (between 1 and
+/// [`MAX_COMMAND_LEN`]) to the output. Lengths are encoded as `len - 1`, so a decoded command is
+/// never empty, and an empty command can't be encoded: [`assemble`] rejects it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command<'a> {
+    /// Copies literal bytes from the compressed stream.
     Copy(&'a [u8]),
-    ByteFill {
-        data: u8,
-        len: usize,
-    },
-    WordFill {
-        data: u16,
-        len: usize,
-    },
-    Incrementing {
-        start: u8,
+
+    /// Repeats a single byte.
+    ByteFill { data: u8, len: usize },
+
+    /// Repeats a little-endian 16-bit word. The last word may be cut short if `len` is odd.
+    WordFill { data: u16, len: usize },
+
+    /// Writes an incrementing (wrapping) sequence of bytes, beginning with `start`.
+    Incrementing { start: u8, len: usize },
+
+    /// Copies previously decompressed output, optionally inverting every bit.
+    ///
+    /// The copied range may overlap the output of this command itself: bytes are copied one at a
+    /// time, so each byte written can in turn be copied. For instance, a relative backreference
+    /// with a distance of `n` and a length greater than `n` repeats the last `n` bytes of output,
+    /// like a fill with an `n`-byte pattern.
+    Backreference {
+        src: Reference,
+        invert: bool,
         len: usize,
     },
+
+    /// Marks the end of the compressed stream.
+    Stop,
+}
+
+impl Command<'_> {
+    /// Returns the number of bytes this command appends to the output.
+    #[allow(clippy::len_without_is_empty)]
+    pub fn len(&self) -> usize {
+        match self {
+            Command::Copy(buf) => buf.len(),
+            Command::ByteFill { data: _, len } => *len,
+            Command::WordFill { data: _, len } => *len,
+            Command::Incrementing { start: _, len } => *len,
+            Command::Backreference {
+                src: _,
+                invert: _,
+                len,
+            } => *len,
+            Command::Stop => 0,
+        }
+    }
+
+    /// Returns the number of bytes this command occupies in the compressed stream: a 1- or 2-byte
+    /// header, followed by its operands.
+    ///
+    /// The 1-byte header is used when the length is at most 32, except for inverted relative
+    /// backreferences, which always use the 2-byte header. This is the exact size, unlike the
+    /// estimates of a [`CostModel`].
+    pub fn encoded_len(&self) -> usize {
+        let args = match self {
+            Command::Copy(buf) => buf.len(),
+            Command::ByteFill { data: _, len: _ } => 1,
+            Command::WordFill { data: _, len: _ } => 2,
+            Command::Incrementing { start: _, len: _ } => 1,
+            Command::Backreference {
+                src: Reference::Relative(_),
+                invert: _,
+                len: _,
+            } => 1,
+            Command::Backreference {
+                src: _,
+                invert: _,
+                len: _,
+            } => 2,
+            Command::Stop => return 1,
+        };
+
+        // Inverted relative backreferences can only be encoded as extended commands
+        let extended = matches!(
+            self,
+            Command::Backreference {
+                src: Reference::Relative(_),
+                invert: true,
+                len: _
+            }
+        );
+
+        if self.len() <= 32 && !extended {
+            args + 1
+        } else {
+            args + 2
+        }
+    }
+}
+
+/// Formats the command as a short, human-readable description, such as `ByteFill 0xAA ×12` or
+/// `Backref rel -12 ×8 inv`.
+impl fmt::Display for Command<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Command::Copy(buf) => write!(f, "Copy({})", buf.len()),
+            Command::ByteFill { data, len } => write!(f, "ByteFill {data:#04X} ×{len}"),
+            Command::WordFill { data, len } => write!(f, "WordFill {data:#06X} ×{len}"),
+            Command::Incrementing { start, len } => write!(f, "Incr from {start:#04X} ×{len}"),
+            Command::Backreference { src, invert, len } => {
+                match src {
+                    Reference::Absolute(i) => write!(f, "Backref abs {i:#06X} ×{len}")?,
+                    Reference::Relative(i) => write!(f, "Backref rel -{i} ×{len}")?,
+                }
+                if *invert {
+                    write!(f, " inv")?;
+                }
+                Ok(())
+            }
+            Command::Stop => write!(f, "Stop"),
+        }
+    }
+}
+
+/// An owned version of [`Command`], which does not borrow from the compressed stream.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum OwnedCommand {
+    /// Copies literal bytes from the compressed stream.
+    Copy(Vec<u8>),
+
+    /// Repeats a single byte.
+    ByteFill { data: u8, len: usize },
+
+    /// Repeats a little-endian 16-bit word. The last word may be cut short if `len` is odd.
+    WordFill { data: u16, len: usize },
+
+    /// Writes an incrementing (wrapping) sequence of bytes, beginning with `start`.
+    Incrementing { start: u8, len: usize },
+
+    /// Copies previously decompressed output, optionally inverting every bit.
     Backreference {
         src: Reference,
         invert: bool,
         len: usize,
     },
+
+    /// Marks the end of the compressed stream.
     Stop,
 }
 
-impl Command<'_> {
-    const MAX_LEN: usize = 0x400;
+impl OwnedCommand {
+    /// Returns the number of bytes this command appends to the output.
+    #[allow(clippy::len_without_is_empty)]
+    pub fn len(&self) -> usize {
+        self.as_command().len()
+    }
+
+    /// Returns the number of bytes this command occupies in the compressed stream. See
+    /// [`Command::encoded_len`].
+    pub fn encoded_len(&self) -> usize {
+        self.as_command().encoded_len()
+    }
+
+    /// Returns a borrowed view of this command.
+    pub fn as_command(&self) -> Command<'_> {
+        match *self {
+            OwnedCommand::Copy(ref buf) => Command::Copy(buf),
+            OwnedCommand::ByteFill { data, len } => Command::ByteFill { data, len },
+            OwnedCommand::WordFill { data, len } => Command::WordFill { data, len },
+            OwnedCommand::Incrementing { start, len } => Command::Incrementing { start, len },
+            OwnedCommand::Backreference { src, invert, len } => {
+                Command::Backreference { src, invert, len }
+            }
+            OwnedCommand::Stop => Command::Stop,
+        }
+    }
+}
+
+impl fmt::Display for OwnedCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.as_command().fmt(f)
+    }
 }
 
-#[derive(Debug)]
-enum Reference {
+impl From<Command<'_>> for OwnedCommand {
+    fn from(cmd: Command<'_>) -> Self {
+        match cmd {
+            Command::Copy(buf) => OwnedCommand::Copy(buf.to_vec()),
+            Command::ByteFill { data, len } => OwnedCommand::ByteFill { data, len },
+            Command::WordFill { data, len } => OwnedCommand::WordFill { data, len },
+            Command::Incrementing { start, len } => OwnedCommand::Incrementing { start, len },
+            Command::Backreference { src, invert, len } => {
+                OwnedCommand::Backreference { src, invert, len }
+            }
+            Command::Stop => OwnedCommand::Stop,
+        }
+    }
+}
+
+/// The location of the data copied by a [`Command::Backreference`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Reference {
+    /// An offset from the beginning of the output. Must be less than the length of the output so
+    /// far.
     Absolute(u16),
+
+    /// A distance backwards from the current end of the output: `Relative(n)` starts copying from
+    /// position `output.len() - n`. Must be between 1 and the length of the output so far, so
+    /// `Relative(output.len())` copies from the first byte of the output.
     Relative(u8),
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use alloc::{vec, vec::Vec};
 
     #[test]
     fn test_decompress() {
@@ -89,61 +344,2527 @@ mod test {
             decompress(&[0x2, 1, 2, 3, 0xFB, 0xFE, 0x3, 0xFF]),
             Ok([1, 2, 3].into_iter().cycle().take(1026).collect())
         );
+
+        assert_eq!(
+            decompress(&[0x23, 0xAA]),
+            Err(DecompressionError::MissingStop { offset: 2 })
+        );
+        assert_eq!(
+            decompress(&[0x43, 0xAA]),
+            Err(DecompressionError::UnexpectedEof { offset: 2 })
+        );
+        assert_eq!(
+            decompress(&[0x23, 0xAA, 0x80, 0x05, 0x00, 0xFF]),
+            Err(DecompressionError::AbsoluteWindowOutOfRange {
+                offset: 2,
+                start: 5,
+                output_len: 4
+            })
+        );
     }
 
     #[test]
-    fn test_compress() {
-        assert_eq!(compress(&[0, 2, 4, 6]), vec![0x03, 0, 2, 4, 6, 0xFF]);
-        assert_eq!(compress(&[1, 1, 1, 1]), vec![0x23, 1, 0xFF]);
-        assert_eq!(compress(&[1, 2, 1, 2, 1, 2]), vec![0x45, 1, 2, 0xFF]);
-        assert_eq!(compress(&[1, 2, 1, 2, 1]), vec![0x44, 1, 2, 0xFF]);
-        assert_eq!(compress(&[1, 2, 3, 4]), vec![0x63, 1, 0xFF]);
+    fn test_decompress_into() {
+        let mut dst = vec![0xAA, 0xBB];
+        decompress_into(&[0x2, 1, 2, 3, 0x85, 0x00, 0x00, 0xFF], &mut dst).unwrap();
+        assert_eq!(dst, [0xAA, 0xBB, 1, 2, 3, 1, 2, 3, 1, 2, 3]);
 
+        // a relative reference may not reach into pre-existing data
+        let mut dst = vec![0xAA, 0xBB];
         assert_eq!(
-            compress(&[1, 2, 3, 4, 1, 2, 3, 4, 1, 2, 3, 4]),
-            vec![0x63, 1, 0xC7, 0x4, 0xFF]
+            decompress_into(&[0xC1, 0x01, 0xFF], &mut dst),
+            Err(DecompressionError::RelativeWindowOutOfRange {
+                offset: 0,
+                distance: 1,
+                output_len: 0
+            })
         );
+    }
 
-        // a relative inverted backreference can only be encoded as an extended command
+    #[test]
+    fn test_decompress_with_consumed() {
         assert_eq!(
-            compress(&[1, 2, 3, 4, !1, !2, !3, !4, 1, 2, 3, 4]),
-            vec![0x63, 1, 0xFC, 0x07, 0x4, 0xFF]
+            decompress_with_consumed(&[0x23, 0xAA, 0xFF, 0x12, 0x34]),
+            Ok((vec![0xAA, 0xAA, 0xAA, 0xAA], 3))
         );
+        assert_eq!(decompress_with_consumed(&[0xFF]), Ok((vec![], 1)));
+    }
 
-        // a relative inverted backreference must not collide with the stop symbol
-        let tricky = [1, 2, 3, 4]
-            .into_iter()
-            .chain([!1, !2, !3, !4, 1, 2, 3, 4].into_iter().cycle().take(0x400))
-            .collect::<Vec<u8>>();
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_reader() {
+        use std::io::Read;
+
+        let data = include_bytes!("green_brinstar_main_shaft.bin");
+        let mut reader = LznintReader::new(data);
+        let mut first = [0; 7];
+        reader.read_exact(&mut first).unwrap();
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest).unwrap();
+
+        let expected = decompress(data).unwrap();
+        assert_eq!(&expected[..7], &first);
+        assert_eq!(&expected[7..], &rest);
+
+        let mut reader = LznintReader::new(&[0xC1, 0x01, 0xFF]);
+        let err = reader.read_to_end(&mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_writer() {
+        let data = decompress(include_bytes!("green_brinstar_main_shaft.bin")).unwrap();
+        let mut writer = LznintWriter::new(Vec::new());
+        std::io::copy(&mut &data[..], &mut writer).unwrap();
+        let compressed = writer.finish().unwrap();
+
+        assert_eq!(compressed, compress(&data));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_decompress_to_writer() {
+        let src = include_bytes!("green_brinstar_main_shaft.bin");
+        let mut out = Vec::new();
+        let written = decompress_to_writer(src, &mut out).unwrap();
+        assert_eq!(out, decompress(src).unwrap());
+        assert_eq!(written, out.len() as u64);
+
+        // Long enough that most of the output is discarded, with absolute references into the
+        // first 64 KiB and relative references near the end.
+        let block = random_bytes(0x800, 7);
+        let mut data = Vec::new();
+        for i in 0..160 {
+            data.extend_from_slice(&block[i % 8 * 0x100..][..0x100]);
+            data.extend_from_slice(&random_bytes(0x20, i as u32 + 100));
+            data.extend_from_within(data.len() - 0x60..data.len() - 0x20);
+        }
+        while data.len() < 0x60000 {
+            data.extend_from_within(0x8000..0x9000);
+        }
+        let src = compress(&data);
+        let mut out = Vec::new();
+        decompress_to_writer(&src, &mut out).unwrap();
+        assert_eq!(out, data);
+
+        let err = decompress_to_writer(&[0xC1, 0x01, 0xFF], Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_decompress_from_reader() {
+        let src = include_bytes!("green_brinstar_main_shaft.bin");
+        let (data, consumed) = decompress_from_reader(&src[..]).unwrap();
+        assert_eq!(data, decompress(src).unwrap());
+        assert_eq!(consumed, src.len());
+
+        let err = decompress_from_reader(&[0xC1, 0x01, 0xFF][..]).unwrap_err();
+        assert!(matches!(
+            err,
+            StreamError::Decompression(DecompressionError::RelativeWindowOutOfRange { .. })
+        ));
         assert_eq!(
-            compress(&tricky),
-            [0x63, 1, 0xFE, 0xFF, 0x4, 0xF8, 0xFF, 0xF8, 0xFF]
+            std::io::Error::from(err).kind(),
+            std::io::ErrorKind::InvalidData
         );
 
-        // create a 512-byte-long non-compressible sequence
-        let seq = || {
-            std::iter::successors(Some(1u8), |&x| Some(x.wrapping_add(3)))
-                .take(256)
-                .flat_map(|i| [i, i.wrapping_sub(1)])
+        struct Failing;
+        impl std::io::Read for Failing {
+            fn read(&mut self, _: &mut [u8]) -> std::io::Result<usize> {
+                Err(std::io::ErrorKind::BrokenPipe.into())
+            }
+        }
+        let err = decompress_from_reader(Failing).unwrap_err();
+        assert!(matches!(&err, StreamError::Io(e) if e.kind() == std::io::ErrorKind::BrokenPipe));
+        assert_eq!(
+            std::io::Error::from(err).kind(),
+            std::io::ErrorKind::BrokenPipe
+        );
+
+        assert_eq!(decompress_reader(&src[..]).unwrap(), data);
+        assert!(matches!(
+            decompress_reader(Failing),
+            Err(StreamError::Io(_))
+        ));
+        assert_eq!(compress_reader(&data[..]).unwrap(), compress(&data));
+        assert_eq!(
+            compress_reader(Failing).unwrap_err().kind(),
+            std::io::ErrorKind::BrokenPipe
+        );
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_error_dedup() {
+        let inputs: [&[u8]; 4] = [&[0x00], &[0xC1, 0x01, 0xFF], &[0x00, 0x01], &[0xC1, 0x01]];
+        let errors = inputs
+            .iter()
+            .map(|src| decompress(src).unwrap_err())
+            .collect::<std::collections::HashSet<_>>();
+        assert_eq!(errors.len(), 3);
+        assert!(errors.contains(&DecompressionError::UnexpectedEof { offset: 1 }));
+
+        let err = decompress(&[0x00]).unwrap_err();
+        assert_eq!(err.clone(), err);
+    }
+
+    #[test]
+    fn test_worst_case_output() {
+        assert_eq!(worst_case_output_for_input_len(0), 0);
+        assert_eq!(worst_case_output_for_input_len(1), 0);
+        assert_eq!(worst_case_output_for_input_len(3), 32);
+        assert_eq!(worst_case_output_for_input_len(4), MAX_COMMAND_LEN);
+        assert_eq!(worst_case_output_for_input_len(5), MAX_COMMAND_LEN);
+        assert_eq!(worst_case_output_for_input_len(6), MAX_COMMAND_LEN + 32);
+        assert_eq!(worst_case_output_for_input_len(usize::MAX), usize::MAX);
+
+        // Exhaustively check every terminated stream of up to 3 bytes, and the densest longer
+        // streams.
+        for i in 0..=0xFFFFu16 {
+            let [a, b] = i.to_le_bytes();
+            for src in [&[a, b, 0xFF][..], &[a, 0xFF]] {
+                if let Ok(data) = decompress(src) {
+                    assert!(data.len() <= worst_case_output_for_input_len(src.len()));
+                }
+            }
+        }
+        let dense = [0xE7, 0xFF, 0x00, 0xE7, 0xFF, 0x00, 0x3F, 0x00, 0xFF];
+        assert_eq!(
+            decompressed_size(&dense),
+            Ok(worst_case_output_for_input_len(dense.len()))
+        );
+        let src = compress(&vec![0; 0x10000]);
+        assert!(decompressed_size(&src).unwrap() <= worst_case_output_for_input_len(src.len()));
+    }
+
+    #[test]
+    fn test_decompress_into_slice() {
+        let src = include_bytes!("green_brinstar_main_shaft.bin");
+        let expected = decompress(src).unwrap();
+        let mut out = vec![0; expected.len() + 10];
+        assert_eq!(decompress_into_slice(src, &mut out), Ok(expected.len()));
+        assert_eq!(out[..expected.len()], expected);
+
+        // Every command type, including overlapping and inverted backreferences
+        let stream = [
+            0x02, 1, 2, 3, 0x23, 0xAA, 0x44, 0x34, 0x12, 0x63, 0xFE, 0x84, 0x01, 0x00, 0xFC, 0x03,
+            0x01, 0xA3, 0x00, 0x00, 0xFF,
+        ];
+        let expected = decompress(&stream).unwrap();
+        let mut out = [0; 64];
+        assert_eq!(decompress_into_slice(&stream, &mut out), Ok(expected.len()));
+        assert_eq!(out[..expected.len()], expected);
+
+        let mut out = vec![0; expected.len() - 1];
+        assert_eq!(
+            decompress_into_slice(&stream, &mut out),
+            Err(DecompressionError::OutputBufferFull { offset: 17 })
+        );
+        assert_eq!(decompress_into_slice(&[0xFF], &mut []), Ok(0));
+        assert_eq!(
+            decompress_into_slice(&[0x00, 1, 0xC1, 0x02, 0xFF], &mut [0; 8]),
+            decompress(&[0x00, 1, 0xC1, 0x02, 0xFF]).map(|_| 0)
+        );
+    }
+
+    #[test]
+    fn test_error_messages() {
+        use alloc::string::ToString;
+
+        let messages = [
+            (
+                DecompressionError::UnexpectedEof { offset: 1 },
+                "Unexpected end of input at offset 1",
+            ),
+            (
+                DecompressionError::AbsoluteWindowOutOfRange {
+                    offset: 2,
+                    start: 3,
+                    output_len: 0,
+                },
+                "Absolute backreference at offset 2 starts at 3, but the output is only 0 bytes long",
+            ),
+            (
+                DecompressionError::RelativeWindowOutOfRange {
+                    offset: 4,
+                    distance: 5,
+                    output_len: 1,
+                },
+                "Relative backreference at offset 4 reaches back 5 bytes, but the output is only 1 \
+                 bytes long",
+            ),
+            (
+                DecompressionError::RelativeZeroOffset { offset: 6 },
+                "Relative backreference at offset 6 has a distance of zero",
+            ),
+            (
+                DecompressionError::MissingStop { offset: 7 },
+                "Input ended without a stop command at offset 7",
+            ),
+            (
+                DecompressionError::OutputTooLarge { offset: 8 },
+                "Output too large at offset 8",
+            ),
+            (
+                DecompressionError::OutputBufferFull { offset: 9 },
+                "Output buffer full at offset 9",
+            ),
+            (
+                DecompressionError::NonCanonicalEncoding { offset: 10 },
+                "Non-canonical encoding for command at offset 10",
+            ),
+            (
+                DecompressionError::RebaseOutOfRange {
+                    offset: 11,
+                    address: -1,
+                },
+                "Absolute backreference at offset 11 can't be rebased to address -1",
+            ),
+            (DecompressionError::BadMagic, "Missing container magic bytes"),
+            (
+                DecompressionError::LengthMismatch {
+                    expected: 12,
+                    actual: 13,
+                },
+                "Expected 12 bytes of decompressed data, but got 13",
+            ),
+        ];
+        for (err, message) in messages {
+            assert_eq!(err.to_string(), message);
+        }
+
+        let messages = [
+            (
+                CompressError::CommandTooLong { index: 1 },
+                "Command 1 is longer than the maximum command length",
+            ),
+            (
+                CompressError::InvertedRelativeTooLong { index: 2 },
+                "Command 2 is an inverted relative backreference longer than 0x300 bytes",
+            ),
+            (
+                CompressError::InvalidRelativeOffset { index: 3 },
+                "Command 3 is a relative backreference with an offset of zero",
+            ),
+            (
+                CompressError::RoundtripMismatch { offset: 4 },
+                "Compressed output does not match the input at offset 4",
+            ),
+            (
+                CompressError::EmptyCommand { index: 5 },
+                "Command 5 has a length of zero",
+            ),
+            (
+                CompressError::EarlyStop { index: 2 },
+                "Command 2 is a stop before the end of the list",
+            ),
+            (
+                CompressError::OverBudget {
+                    size: 300,
+                    budget: 86,
+                },
+                "Compressed output is 300 bytes, 214 bytes over the budget of 86 bytes",
+            ),
+            (
+                CompressError::InputTooLarge { len: 0x10001 },
+                "Input of 65537 bytes is longer than the maximum of 0x10000 bytes",
+            ),
+        ];
+        for (err, message) in messages {
+            assert_eq!(err.to_string(), message);
+        }
+    }
+
+    #[test]
+    fn test_relative_boundary() {
+        // 3 bytes of output, then Relative(3) copies from byte 0 and Relative(4) is out of range.
+        let valid = [0x02, 1, 2, 3, 0xC1, 0x03, 0xFF];
+        let invalid = [0x02, 1, 2, 3, 0xC1, 0x04, 0xFF];
+        assert_eq!(decompress(&valid), Ok(vec![1, 2, 3, 1, 2]));
+        let err = DecompressionError::RelativeWindowOutOfRange {
+            offset: 4,
+            distance: 4,
+            output_len: 3,
         };
+        assert_eq!(decompress(&invalid), Err(err.clone()));
+
+        // Every decoder agrees on both streams.
+        assert_eq!(decompressed_size(&valid), Ok(5));
+        assert_eq!(decompressed_size(&invalid), Err(err.clone()));
+        assert_eq!(validate(&invalid), Err(err.clone()));
+        let mut out = [0; 8];
+        assert_eq!(decompress_into_slice(&valid, &mut out), Ok(5));
+        assert_eq!(decompress_into_slice(&invalid, &mut out), Err(err.clone()));
         assert_eq!(
-            compress(&seq().chain(seq()).collect::<Vec<u8>>()),
-            [0xE1, 0xFF]
-                .into_iter()
-                .chain(seq())
-                .chain([0xF1, 0xFF, 0x00, 0x00, 0xFF])
-                .collect::<Vec<_>>()
+            decompress_iter(&valid).collect::<Result<Vec<u8>, _>>(),
+            Ok(vec![1, 2, 3, 1, 2])
+        );
+        assert_eq!(
+            decompress_iter(&invalid).collect::<Result<Vec<u8>, _>>(),
+            Err(err)
+        );
+
+        // The same boundary applies to absolute references: the last byte of the output is
+        // addressable, and the next one isn't.
+        assert_eq!(
+            decompress(&[0x02, 1, 2, 3, 0x81, 0x02, 0x00, 0xFF]),
+            Ok(vec![1, 2, 3, 3, 3])
+        );
+        assert_eq!(
+            decompress(&[0x02, 1, 2, 3, 0x81, 0x03, 0x00, 0xFF]),
+            Err(DecompressionError::AbsoluteWindowOutOfRange {
+                offset: 4,
+                start: 3,
+                output_len: 3
+            })
         );
     }
 
     #[test]
-    fn test_roundtrip_green_brinstar() {
-        let data = include_bytes!("green_brinstar_main_shaft.bin");
+    fn test_decompress_with_crc() {
+        // The standard CRC-32 check value
+        let src = compress(b"123456789");
+        assert_eq!(
+            decompress_with_crc(&src),
+            Ok((b"123456789".to_vec(), 0xCBF43926))
+        );
+        assert_eq!(decompress_with_crc(&[0xFF]), Ok((vec![], 0)));
 
-        let decompressed = decompress(data).unwrap();
-        let recompressed = compress(&decompressed);
-        let redecompressed = decompress(&recompressed).unwrap();
+        let src = include_bytes!("green_brinstar_main_shaft.bin");
+        let (data, crc) = decompress_with_crc(src).unwrap();
+        assert_eq!(data, decompress(src).unwrap());
+        assert_eq!(crc, 0xC4E2B454);
 
-        assert_eq!(decompressed, redecompressed);
+        assert_eq!(
+            decompress_with_crc(&[0xC1, 0x01, 0xFF]),
+            Err(decompress(&[0xC1, 0x01, 0xFF]).unwrap_err())
+        );
+    }
+
+    #[test]
+    fn test_misplaced_stop() {
+        // 0xFF as an operand is data, not a stop.
+        assert_eq!(decompress(&[0x23, 0xFF, 0xFF]), Ok(vec![0xFF; 4]));
+        assert_eq!(decompress(&[0x43, 0xFF, 0xFF, 0xFF]), Ok(vec![0xFF; 4]));
+        assert_eq!(decompress(&[0x01, 0xFF, 0xFF, 0xFF]), Ok(vec![0xFF; 2]));
+
+        // A word fill whose header was corrupted into a byte fill reads the second byte of the
+        // word as the next command: here, a stop, so the output is silently truncated.
+        let original = [0x43, 0x12, 0xFF, 0x00, 0x07, 0xFF];
+        assert_eq!(
+            decompress(&original),
+            Ok(vec![0x12, 0xFF, 0x12, 0xFF, 0x07])
+        );
+        let corrupted = [0x23, 0x12, 0xFF, 0x00, 0x07, 0xFF];
+        assert_eq!(decompress(&corrupted), Ok(vec![0x12; 4]));
+        assert_eq!(validate(&corrupted), Ok(3));
+
+        // Dropping an operand makes the stop byte part of the previous command instead, so the
+        // stream runs off the end.
+        assert_eq!(
+            decompress(&[0x43, 0x12, 0xFF]),
+            Err(DecompressionError::MissingStop { offset: 3 })
+        );
+        // A stop where an operand was expected is also read as an operand.
+        assert_eq!(
+            decompress(&[0x80, 0xFF]),
+            Err(DecompressionError::UnexpectedEof { offset: 2 })
+        );
+    }
+
+    #[test]
+    fn test_pack() {
+        let data = decompress(include_bytes!("green_brinstar_main_shaft.bin")).unwrap();
+        let packed = pack(&data);
+        assert_eq!(packed[..4], PACK_MAGIC);
+        assert_eq!(packed[4..8], (data.len() as u32).to_le_bytes());
+        assert_eq!(packed[8..], compress(&data));
+        assert_eq!(unpack(&packed), Ok(data));
+
+        assert_eq!(pack(&[]), [b'L', b'Z', b'N', b'T', 0, 0, 0, 0, 0xFF]);
+        assert_eq!(unpack(&pack(&[])), Ok(vec![]));
+
+        assert_eq!(unpack(&[0x00, 1, 0xFF]), Err(DecompressionError::BadMagic));
+        assert_eq!(
+            unpack(b"LZNT\x01\x00"),
+            Err(DecompressionError::UnexpectedEof { offset: 6 })
+        );
+        assert_eq!(
+            unpack(b"LZNT\x02\x00\x00\x00\x00\x01\xFF"),
+            Err(DecompressionError::LengthMismatch {
+                expected: 2,
+                actual: 1
+            })
+        );
+        // Offsets in stream errors include the header
+        assert_eq!(
+            unpack(b"LZNT\x01\x00\x00\x00\x00"),
+            Err(DecompressionError::UnexpectedEof { offset: 9 })
+        );
+    }
+
+    #[test]
+    fn test_decompressed_size() {
+        let src = include_bytes!("green_brinstar_main_shaft.bin");
+        assert_eq!(decompressed_size(src), Ok(decompress(src).unwrap().len()));
+        assert_eq!(decompressed_size(&[0xFF]), Ok(0));
+        assert_eq!(decompressed_size(&[0x23, 0xAA, 0xC5, 0x03, 0xFF]), Ok(10));
+        assert_eq!(max_output_size(src), decompressed_size(src));
+
+        for src in [
+            &[0x23, 0xAA][..],
+            &[0x23],
+            &[0x23, 0xAA, 0x81, 0x04, 0x00, 0xFF],
+            &[0x23, 0xAA, 0xC1, 0x05, 0xFF],
+            &[0x23, 0xAA, 0xC1, 0x00, 0xFF],
+        ] {
+            assert_eq!(decompressed_size(src), decompress(src).map(|x| x.len()));
+            assert_eq!(command_count(src).err(), decompress(src).err());
+        }
+
+        assert_eq!(command_count(&[0xFF]), Ok(0));
+        assert_eq!(command_count(&[0x23, 0xAA, 0xC5, 0x03, 0xFF]), Ok(2));
+        assert_eq!(command_count(src), Ok(disassemble(src).unwrap().len() - 1));
+    }
+
+    #[test]
+    fn test_compress_bound() {
+        assert_eq!(compress_bound(0), 1);
+        assert_eq!(compress_bound(32), 34);
+        assert_eq!(compress_bound(33), 36);
+        assert_eq!(compress_bound(0x400), 0x403);
+        assert_eq!(compress_bound(0x401), 0x405);
+
+        for seed in 1..20 {
+            let len = random_bytes(1, seed)[0] as usize * 4 + seed as usize;
+            let mut data = random_bytes(len, seed);
+            assert!(compress(&data).len() <= compress_bound(len));
+
+            // Short runs are only just worth compressing, and split up the literal copies.
+            for i in (0..len.saturating_sub(8)).step_by(37) {
+                let byte = data[i];
+                data[i..i + 5].fill(byte);
+            }
+            assert!(compress(&data).len() <= compress_bound(len));
+        }
+
+        let data = random_bytes(0x845, 99);
+        assert_eq!(compress(&data).len(), compress_bound(data.len()));
+    }
+
+    #[test]
+    fn test_validate() {
+        let src = include_bytes!("green_brinstar_main_shaft.bin");
+        assert_eq!(validate(src), Ok(src.len()));
+        assert_eq!(validate(&[0x23, 0xAA, 0xFF, 0x12, 0x34]), Ok(3));
+        assert_eq!(
+            validate(&[0x23, 0xAA, 0x81, 0x04, 0x00, 0xFF]),
+            Err(DecompressionError::AbsoluteWindowOutOfRange {
+                offset: 2,
+                start: 4,
+                output_len: 4
+            })
+        );
+        assert_eq!(
+            validate(&[0x23, 0xAA]),
+            Err(DecompressionError::MissingStop { offset: 2 })
+        );
+    }
+
+    #[test]
+    fn test_decompress_lenient() {
+        let src = include_bytes!("green_brinstar_main_shaft.bin");
+        assert_eq!(decompress_lenient(src), (decompress(src).unwrap(), None));
+
+        assert_eq!(
+            decompress_lenient(&[0x23, 0xAA, 0x02, 1, 2, 3, 0x81, 0x20, 0x00, 0xFF]),
+            (
+                vec![0xAA, 0xAA, 0xAA, 0xAA, 1, 2, 3],
+                Some(DecompressionError::AbsoluteWindowOutOfRange {
+                    offset: 6,
+                    start: 32,
+                    output_len: 7
+                })
+            )
+        );
+        assert_eq!(
+            decompress_lenient(&[0x23, 0xAA, 0x03, 1, 2]),
+            (
+                vec![0xAA, 0xAA, 0xAA, 0xAA],
+                Some(DecompressionError::UnexpectedEof { offset: 5 })
+            )
+        );
+    }
+
+    #[test]
+    fn test_display() {
+        use alloc::string::ToString;
+
+        let cmds = [
+            (Command::Copy(&[1, 2, 3, 4]), "Copy(4)"),
+            (
+                Command::ByteFill {
+                    data: 0xAA,
+                    len: 12,
+                },
+                "ByteFill 0xAA ×12",
+            ),
+            (
+                Command::WordFill {
+                    data: 0x55AA,
+                    len: 8,
+                },
+                "WordFill 0x55AA ×8",
+            ),
+            (
+                Command::Incrementing { start: 1, len: 4 },
+                "Incr from 0x01 ×4",
+            ),
+            (
+                Command::Backreference {
+                    src: Reference::Relative(12),
+                    invert: true,
+                    len: 8,
+                },
+                "Backref rel -12 ×8 inv",
+            ),
+            (
+                Command::Backreference {
+                    src: Reference::Absolute(0x1234),
+                    invert: false,
+                    len: 8,
+                },
+                "Backref abs 0x1234 ×8",
+            ),
+            (Command::Stop, "Stop"),
+        ];
+        for (cmd, expected) in cmds {
+            assert_eq!(cmd.to_string(), expected);
+            assert_eq!(OwnedCommand::from(cmd).to_string(), expected);
+        }
+    }
+
+    #[test]
+    fn test_decompress_at() {
+        let rom = [0x12, 0x34, 0x23, 0xAA, 0xFF, 0x01, 0x56, 0x78, 0xFF, 0xC1];
+        assert_eq!(decompress_at(&rom, 2), Ok((vec![0xAA; 4], 5)));
+        assert_eq!(decompress_at(&rom, 5), Ok((vec![0x56, 0x78], 9)));
+        assert_eq!(
+            decompress_at(&rom, 9),
+            Err(DecompressionError::UnexpectedEof { offset: 10 })
+        );
+        assert_eq!(
+            decompress_at(&rom, 10),
+            Err(DecompressionError::MissingStop { offset: 10 })
+        );
+        assert_eq!(
+            decompress_at(&rom, 11),
+            Err(DecompressionError::UnexpectedEof { offset: 10 })
+        );
+    }
+
+    #[test]
+    fn test_compressor() {
+        let data = decompress(include_bytes!("green_brinstar_main_shaft.bin")).unwrap();
+        let mut compressor = Compressor::new();
+        for block in [&data[..0x1000], &data[0x3000..0x3800], &[], &data[..0x1000]] {
+            assert_eq!(compressor.compress(block), compress(block));
+        }
+
+        let opts = CompressOptions::from(CompressMode::SizeOptimal);
+        let mut compressor = Compressor::with_options(opts.clone());
+        assert_eq!(
+            compressor.compress(&data[..0x800]),
+            compress_with(&data[..0x800], &opts)
+        );
+        compressor.reset();
+        assert_eq!(
+            compressor.compress(&data[..0x800]),
+            compress_with(&data[..0x800], &opts)
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde() {
+        let cmds = [
+            OwnedCommand::Copy(vec![1, 2]),
+            OwnedCommand::ByteFill { data: 170, len: 12 },
+            OwnedCommand::Backreference {
+                src: Reference::Relative(2),
+                invert: true,
+                len: 4,
+            },
+            OwnedCommand::Stop,
+        ];
+        let json = serde_json::to_string(&cmds).unwrap();
+        assert_eq!(
+            json,
+            r#"[{"Copy":[1,2]},{"ByteFill":{"data":170,"len":12}},{"Backreference":{"src":{"Relative":2},"invert":true,"len":4}},"Stop"]"#
+        );
+        assert_eq!(
+            serde_json::from_str::<Vec<OwnedCommand>>(&json).unwrap(),
+            cmds
+        );
+
+        let src = include_bytes!("green_brinstar_main_shaft.bin");
+        let cmds = disassemble(src).unwrap();
+        let cmds: Vec<(usize, OwnedCommand)> =
+            serde_json::from_str(&serde_json::to_string(&cmds).unwrap()).unwrap();
+        let cmds = cmds.into_iter().map(|(_, cmd)| cmd).collect::<Vec<_>>();
+        assert_eq!(assemble(&cmds).unwrap(), src);
+    }
+
+    #[test]
+    fn test_compress_checked() {
+        let data = decompress(include_bytes!("green_brinstar_main_shaft.bin")).unwrap();
+        assert_eq!(compress_checked(&data), Ok(compress(&data)));
+
+        let opts = CompressOptions {
+            verify: true,
+            ..CompressMode::SizeOptimal.into()
+        };
+        assert_eq!(decompress(&compress_with(&data, &opts)).unwrap(), data);
+
+        assert_eq!(
+            compress::verify(&[1, 2, 3], &[0x02, 1, 2, 3, 0xFF], true),
+            Ok(())
+        );
+        assert_eq!(
+            compress::verify(&[1, 2, 3], &[0x02, 1, 2, 4, 0xFF], true),
+            Err(CompressError::RoundtripMismatch { offset: 2 })
+        );
+        assert_eq!(
+            compress::verify(&[1, 2, 3], &[0x01, 1, 2, 0xFF], true),
+            Err(CompressError::RoundtripMismatch { offset: 2 })
+        );
+        assert_eq!(
+            compress::verify(&[1, 2], &[0x02, 1, 2, 3, 0xFF], true),
+            Err(CompressError::RoundtripMismatch { offset: 2 })
+        );
+        assert_eq!(
+            compress::verify(&[1, 2], &[0x01, 1, 2], true),
+            Err(CompressError::RoundtripMismatch { offset: 2 })
+        );
+    }
+
+    #[cfg(feature = "arbitrary")]
+    #[test]
+    fn test_fuzz() {
+        use arbitrary::{Arbitrary, Unstructured};
+
+        for seed in 1..200 {
+            let data = random_bytes(seed as usize, seed);
+            fuzz::fuzz_decompress(&data);
+            fuzz::fuzz_roundtrip(&data);
+            if let Ok(input) = fuzz::RoundtripInput::arbitrary(&mut Unstructured::new(&data)) {
+                fuzz::fuzz_roundtrip_with(&input);
+            }
+
+            // Streams made of valid command headers reach further than random bytes do.
+            let mut stream = data.clone();
+            stream.iter_mut().step_by(3).for_each(|b| *b &= 0xE7);
+            fuzz::fuzz_decompress(&stream);
+        }
+        fuzz::fuzz_decompress(&[0x81, 0xFF, 0xFF, 0xFF]);
+        fuzz::fuzz_decompress(&[0x00, 0x00, 0x80, 0x01, 0x00, 0xFF]);
+    }
+
+    #[test]
+    fn test_compression_ratio() {
+        assert_eq!(compression_ratio(8, 5), 0.625);
+        assert_eq!(compression_ratio(0, 1), 1.0);
+
+        let data = decompress(include_bytes!("green_brinstar_main_shaft.bin")).unwrap();
+        let (compressed, ratio) = compress_reporting(&data);
+        assert_eq!(compressed, compress(&data));
+        assert_eq!(ratio, compressed.len() as f64 / data.len() as f64);
+        assert_eq!(compress_reporting(&[]), (vec![0xFF], 1.0));
+    }
+
+    #[test]
+    fn test_empty() {
+        assert_eq!(compress(&[]), vec![0xFF]);
+        assert_eq!(decompress(&[0xFF]), Ok(vec![]));
+        assert_eq!(
+            decompress(&[]),
+            Err(DecompressionError::MissingStop { offset: 0 })
+        );
+        for mode in [CompressMode::DecodeSpeed, CompressMode::SizeOptimal] {
+            assert_eq!(compress_with_mode(&[], mode), vec![0xFF]);
+        }
+    }
+
+    #[test]
+    fn test_peephole() {
+        // A 3-byte fill saves a byte on its own, but costs more than that in copy headers when it
+        // splits a run of literals longer than 32 bytes.
+        let mut data = random_bytes(40, 1);
+        data.extend_from_slice(&[0xAA; 3]);
+        data.extend_from_slice(&random_bytes(40, 2));
+        let mode = CompressMode::SizeOptimal;
+        let plain = compress_with_mode(&data, mode);
+        let commands = |stream: &[u8]| -> Vec<OwnedCommand> {
+            disassemble(stream)
+                .unwrap()
+                .into_iter()
+                .map(|(_, cmd)| cmd)
+                .collect()
+        };
+        assert_eq!(
+            commands(&plain),
+            [
+                Command::Copy(&data[..40]),
+                Command::ByteFill { data: 0xAA, len: 3 },
+                Command::Copy(&data[43..]),
+                Command::Stop,
+            ]
+            .map(OwnedCommand::from)
+        );
+        let opts = CompressOptions {
+            peephole: true,
+            ..mode.into()
+        };
+        let folded = compress_with(&data, &opts);
+        assert_eq!(folded.len() + 1, plain.len());
+        assert_eq!(
+            commands(&folded),
+            [Command::Copy(&data), Command::Stop].map(OwnedCommand::from)
+        );
+
+        // Folding never makes the output larger.
+        let data = decompress(include_bytes!("green_brinstar_main_shaft.bin")).unwrap();
+        for mode in [CompressMode::DecodeSpeed, CompressMode::SizeOptimal] {
+            let opts = CompressOptions {
+                peephole: true,
+                ..mode.into()
+            };
+            let folded = compress_with(&data, &opts);
+            assert!(folded.len() <= compress_with_mode(&data, mode).len());
+            assert_eq!(decompress(&folded).unwrap(), data);
+        }
+
+        // Merged literals are still split at the maximum command length.
+        let src = [0; 0x500];
+        let mut merged = Vec::new();
+        compress::peephole(
+            &src,
+            0,
+            MAX_COMMAND_LEN,
+            &[Command::Copy(&src[..0x300]), Command::Copy(&src[0x300..])],
+            |cmd| merged.push(cmd),
+        );
+        assert_eq!(
+            merged,
+            [Command::Copy(&src[..0x400]), Command::Copy(&src[0x400..])]
+        );
+    }
+
+    #[test]
+    fn test_canonicalize() {
+        // Extended forms of a 4-byte byte fill and a 3-byte copy, then trailing data.
+        let stream = [0xE4, 0x03, 0xAA, 0xE0, 0x02, 1, 2, 3, 0xFF, 0x12];
+        let canonical = canonicalize(&stream).unwrap();
+        assert_eq!(canonical, [0x23, 0xAA, 0x02, 1, 2, 3, 0xFF]);
+        assert_eq!(decompress(&canonical), decompress(&stream));
+
+        let src = include_bytes!("green_brinstar_main_shaft.bin");
+        let canonical = canonicalize(src).unwrap();
+        assert_eq!(canonicalize(&canonical).unwrap(), canonical);
+        assert_eq!(decompress(&canonical), decompress(src));
+
+        let data = decompress(src).unwrap();
+        assert_eq!(canonicalize(&compress(&data)).unwrap(), compress(&data));
+
+        assert_eq!(
+            canonicalize(&[0x23, 0xAA, 0xC1, 0x00, 0xFF]),
+            Err(DecompressionError::RelativeZeroOffset { offset: 2 })
+        );
+    }
+
+    #[test]
+    fn test_decompress_strict() {
+        let data = decompress(include_bytes!("green_brinstar_main_shaft.bin")).unwrap();
+        for mode in [CompressMode::DecodeSpeed, CompressMode::SizeOptimal] {
+            let compressed = compress_with_mode(&data, mode);
+            assert_eq!(decompress_strict(&compressed), Ok(data.clone()));
+        }
+
+        assert_eq!(
+            decompress_strict(&[0x23, 0xAA, 0xE4, 0x03, 0xAA, 0xFF]),
+            Err(DecompressionError::NonCanonicalEncoding { offset: 2 })
+        );
+        assert_eq!(
+            decompress_strict(&[0x23, 0xAA, 0xC1, 0x00, 0xFF]),
+            Err(DecompressionError::NonCanonicalEncoding { offset: 2 })
+        );
+        // Inverted relative backreferences always use the extended form.
+        assert_eq!(
+            decompress_strict(&[0x23, 0xAA, 0xFC, 0x01, 0x01, 0xFF]),
+            Ok(vec![0xAA, 0xAA, 0xAA, 0xAA, 0x55, 0xAA])
+        );
+        assert_eq!(
+            decompress_strict(&[0xE4, 0x20, 0xAA, 0xFF]).map(|d| d.len()),
+            Ok(33)
+        );
+    }
+
+    #[test]
+    fn test_compress_many() {
+        let data = decompress(include_bytes!("green_brinstar_main_shaft.bin")).unwrap();
+        let blocks = data.chunks(0x1800).collect::<Vec<_>>();
+        let compressed = compress_many(&blocks);
+        assert_eq!(compressed.len(), blocks.len());
+        for (block, compressed) in blocks.iter().zip(&compressed) {
+            assert_eq!(*compressed, compress(block));
+        }
+        assert_eq!(compress_many(&[]), Vec::<Vec<u8>>::new());
+    }
+
+    #[test]
+    fn test_compress_relative_only() {
+        let data = decompress(include_bytes!("green_brinstar_main_shaft.bin")).unwrap();
+        let opts = CompressOptions {
+            relative_only: true,
+            ..Default::default()
+        };
+        let compressed = compress_with(&data, &opts);
+        assert_eq!(decompress(&compressed).unwrap(), data);
+        assert!(compressed.len() > compress(&data).len());
+        for cmd in commands(&compressed) {
+            assert!(!matches!(
+                cmd.unwrap(),
+                Command::Backreference {
+                    src: Reference::Absolute(_),
+                    invert: _,
+                    len: _
+                }
+            ));
+        }
+
+        // The stream decodes the same way after unrelated output.
+        assert_eq!(
+            decompress_with_prefix(&compressed, &[0x12; 300]).unwrap(),
+            data
+        );
+    }
+
+    #[test]
+    fn test_decode_map() {
+        assert_eq!(
+            decode_map(&[0x2, 1, 2, 3, 0xC5, 0x03, 0xFF]),
+            Ok((
+                vec![1, 2, 3, 1, 2, 3, 1, 2, 3],
+                vec![(0, 3), (3, 6), (9, 0)]
+            ))
+        );
+
+        let src = include_bytes!("green_brinstar_main_shaft.bin");
+        let (data, ranges) = decode_map(src).unwrap();
+        assert_eq!(data, decompress(src).unwrap());
+        let cmds = disassemble(src).unwrap();
+        assert_eq!(ranges.len(), cmds.len());
+        let mut pos = 0;
+        for ((start, len), (_, cmd)) in ranges.into_iter().zip(cmds) {
+            assert_eq!(start, pos);
+            assert_eq!(len, cmd.as_command().len());
+            pos += len;
+        }
+
+        assert_eq!(
+            decode_map(&[0x2, 1, 2]),
+            Err(DecompressionError::UnexpectedEof { offset: 3 })
+        );
+    }
+
+    #[test]
+    fn test_annotated_hexdump() {
+        let mut src = vec![0x2, 1, 2, 3, 0xC5, 0x03, 0x0A];
+        src.extend(0..11);
+        src.push(0xFF);
+        assert_eq!(
+            annotated_hexdump(&src).unwrap(),
+            "000000  02 01 02 03                  Copy(3)\n\
+             000004  C5 03                        Backref rel -3 ×6\n\
+             000006  0A 00 01 02 03 04 05 06 ...  Copy(11)\n\
+             000012  FF                           Stop\n"
+        );
+
+        let src = include_bytes!("green_brinstar_main_shaft.bin");
+        let dump = annotated_hexdump(src).unwrap();
+        assert_eq!(dump.lines().count(), disassemble(src).unwrap().len());
+
+        // Only parse errors are reported
+        assert!(annotated_hexdump(&[0xC5, 0x03, 0xFF]).is_ok());
+        assert_eq!(
+            annotated_hexdump(&[0x02, 1]),
+            Err(DecompressionError::UnexpectedEof { offset: 2 })
+        );
+    }
+
+    #[test]
+    fn test_decompress_to_sink() {
+        let src = include_bytes!("green_brinstar_main_shaft.bin");
+        let mut out = Vec::new();
+        assert_eq!(decompress_to_sink(&src[..], &mut out), Ok(src.len()));
+        assert_eq!(out, decompress(src).unwrap());
+
+        // A sink that keeps only the window relative backreferences can reach
+        struct Window {
+            recent: [u8; 0x100],
+            written: usize,
+            sum: u64,
+        }
+        impl DecodeSink for Window {
+            fn written(&self) -> usize {
+                self.written
+            }
+            fn push(&mut self, byte: u8) {
+                self.recent[self.written % 0x100] = byte;
+                self.written += 1;
+                self.sum += byte as u64;
+            }
+            fn back(&self, distance: usize) -> u8 {
+                assert!(distance <= MAX_RELATIVE_DISTANCE);
+                self.recent[(self.written - distance) % 0x100]
+            }
+        }
+        let data = decompress(src).unwrap();
+        let opts = CompressOptions {
+            relative_only: true,
+            ..Default::default()
+        };
+        let mut window = Window {
+            recent: [0; 0x100],
+            written: 0,
+            sum: 0,
+        };
+        decompress_to_sink(&compress_with(&data, &opts), &mut window).unwrap();
+        assert_eq!(window.written, data.len());
+        assert_eq!(window.sum, data.iter().map(|&x| x as u64).sum::<u64>());
+
+        assert_eq!(
+            decompress_to_sink(&[0x00, 1, 0xC0, 0x02, 0xFF], &mut Vec::new()),
+            Err(DecompressionError::RelativeWindowOutOfRange {
+                offset: 2,
+                distance: 2,
+                output_len: 1
+            })
+        );
+    }
+
+    #[test]
+    fn test_decompress_traced() {
+        let src = include_bytes!("green_brinstar_main_shaft.bin");
+        let mut trace = Vec::new();
+        let data = decompress_traced(src, |offset, cmd| {
+            trace.push((offset, OwnedCommand::from(*cmd)))
+        })
+        .unwrap();
+        assert_eq!(data, decompress(src).unwrap());
+        assert_eq!(trace, disassemble(src).unwrap());
+
+        // The failing command is traced before the error is returned
+        let mut trace = Vec::new();
+        assert_eq!(
+            decompress_traced(&[0x2, 1, 2, 3, 0xC5, 0x10, 0xFF], |offset, cmd| {
+                trace.push((offset, *cmd))
+            }),
+            Err(DecompressionError::RelativeWindowOutOfRange {
+                offset: 4,
+                distance: 0x10,
+                output_len: 3
+            })
+        );
+        assert_eq!(
+            trace,
+            [
+                (0, Command::Copy(&[1, 2, 3])),
+                (
+                    4,
+                    Command::Backreference {
+                        src: Reference::Relative(0x10),
+                        invert: false,
+                        len: 6
+                    }
+                )
+            ]
+        );
+    }
+
+    #[test]
+    fn test_decode_cost() {
+        // A 4-byte byte fill (cost 2), a 6-byte relative backreference (cost 4), and a stop (1).
+        let stream = [0x23, 0xAA, 0xC5, 0x03, 0xFF];
+        assert_eq!(decode_cost(&stream), Ok(7));
+        assert_eq!(decode_cost_with(&stream, &SizeCostModel), Ok(stream.len()));
+
+        let data = decompress(include_bytes!("green_brinstar_main_shaft.bin")).unwrap();
+        let fast = compress(&data);
+        let small = compress_with_mode(&data, CompressMode::SizeOptimal);
+        assert!(decode_cost(&fast).unwrap() < decode_cost(&small).unwrap());
+
+        assert_eq!(
+            decode_cost(&[0xC5, 0x03, 0xFF]),
+            Err(DecompressionError::RelativeWindowOutOfRange {
+                offset: 0,
+                distance: 3,
+                output_len: 0
+            })
+        );
+
+        assert_eq!(fits_in_cycles(&stream, 7, &DefaultCostModel), Ok(true));
+        assert_eq!(fits_in_cycles(&stream, 6, &DefaultCostModel), Ok(false));
+        let cycles = |cmd: &Command| SnesCycleCost.cycles(cmd);
+        let total = decode_cost_with(&stream, &cycles).unwrap() as u64;
+        assert_eq!(fits_in_cycles(&stream, total, &cycles), Ok(true));
+        assert_eq!(fits_in_cycles(&stream, total - 1, &cycles), Ok(false));
+        assert!(fits_in_cycles(&[0xC5, 0x03, 0xFF], u64::MAX, &cycles).is_err());
+    }
+
+    #[test]
+    fn test_overlapping_backreference() {
+        // Relative(1) acts like a byte fill, and Relative(3) repeats a 3-byte pattern.
+        assert_eq!(decompress(&[0x00, 7, 0xC4, 0x01, 0xFF]), Ok(vec![7; 6]));
+        assert_eq!(
+            decompress(&[0x02, 1, 2, 3, 0xC6, 0x03, 0xFF]),
+            Ok(vec![1, 2, 3, 1, 2, 3, 1, 2, 3, 1])
+        );
+        // An inverted overlapping copy alternates, since it copies bytes it has already inverted.
+        assert_eq!(
+            decompress(&[0x00, 0x0F, 0xFC, 0x03, 0x01, 0xFF]),
+            Ok(vec![0x0F, 0xF0, 0x0F, 0xF0, 0x0F])
+        );
+        // An absolute backreference may overlap too.
+        assert_eq!(
+            decompress(&[0x01, 1, 2, 0x84, 0x00, 0x00, 0xFF]),
+            Ok(vec![1, 2, 1, 2, 1, 2, 1])
+        );
+
+        // The compressor uses overlapping backreferences for repeated patterns that no fill
+        // command can encode.
+        let data = [1, 2, 3].repeat(20);
+        assert_eq!(compress(&data), [0x02, 1, 2, 3, 0xF8, 0x38, 0x03, 0xFF]);
+    }
+
+    #[test]
+    fn test_fill_early_return() {
+        let mut data = random_bytes(0x100, 3);
+        data.extend_from_slice(&[0x12; 0x500]);
+
+        // Without the early return, the run is found to be a cheaper byte fill.
+        let opts = CompressOptions {
+            fill_early_return: false,
+            ..Default::default()
+        };
+        let compressed = compress_with(&data, &opts);
+        assert_eq!(compressed.len() + 1, compress(&data).len());
+        assert_eq!(
+            compressed[compressed.len() - 7..],
+            [0xE7, 0xFF, 0x12, 0xE4, 0xFF, 0x12, 0xFF]
+        );
+
+        // A cost model that prefers backreferences only gets to see them without the early return.
+        let cost = |cmd: &Command| match cmd {
+            Command::WordFill { data: _, len: _ } => 8,
+            _ => DefaultCostModel.cost(cmd),
+        };
+        let count_fills = |compressed: &[u8]| {
+            commands(compressed)
+                .filter(|cmd| matches!(cmd, Ok(Command::WordFill { data: _, len: _ })))
+                .count()
+        };
+        let with_early_return = compress_with_cost_model(&data, &Default::default(), &cost);
+        let without = compress_with_cost_model(&data, &opts, &cost);
+        assert!(count_fills(&with_early_return) > count_fills(&without));
+        assert_eq!(decompress(&without).unwrap(), data);
+    }
+
+    #[test]
+    fn test_prefer_word_fill() {
+        let opts = CompressOptions {
+            prefer_word_fill: true,
+            ..Default::default()
+        };
+        assert_eq!(compress(&[0xAA; 32]), [0x3F, 0xAA, 0xFF]);
+        assert_eq!(compress_with(&[0xAA; 32], &opts), [0x5F, 0xAA, 0xAA, 0xFF]);
+
+        // Odd-length runs are still byte fills
+        assert_eq!(compress_with(&[0xAA; 31], &opts), [0x3E, 0xAA, 0xFF]);
+
+        let data = decompress(include_bytes!("green_brinstar_main_shaft.bin")).unwrap();
+        assert_eq!(decompress(&compress_with(&data, &opts)).unwrap(), data);
+    }
+
+    #[test]
+    fn test_max_command_len() {
+        let data = decompress(include_bytes!("green_brinstar_main_shaft.bin")).unwrap();
+        let data = &data[..0x2000];
+        for max_command_len in [1, 0x21, 0x100] {
+            for opts in [
+                CompressOptions::default(),
+                CompressOptions::from(CompressMode::SizeOptimal),
+                CompressOptions {
+                    peephole: true,
+                    lazy: true,
+                    ..Default::default()
+                },
+            ] {
+                let opts = CompressOptions {
+                    max_command_len,
+                    ..opts
+                };
+                let compressed = compress_with(data, &opts);
+                assert_eq!(decompress(&compressed).unwrap(), data);
+                for cmd in commands(&compressed) {
+                    assert!(cmd.unwrap().len() <= max_command_len);
+                }
+            }
+        }
+
+        // Long runs are split into several commands of the maximum length
+        let opts = CompressOptions {
+            max_command_len: 0x100,
+            ..Default::default()
+        };
+        let run = (0..0x300).map(|x| x as u8).collect::<Vec<_>>();
+        assert_eq!(
+            compress_with(&run, &opts),
+            [0xEC, 0xFF, 0x00, 0xEC, 0xFF, 0x00, 0xEC, 0xFF, 0x00, 0xFF]
+        );
+        let mut words = [0x12, 0x34].repeat(0x100);
+        words.push(0x12);
+        let compressed = compress_with(
+            &words,
+            &CompressOptions {
+                max_command_len: 0x101,
+                ..Default::default()
+            },
+        );
+        assert_eq!(decompress(&compressed).unwrap(), words);
+    }
+
+    #[test]
+    #[should_panic(expected = "max_command_len")]
+    fn test_max_command_len_out_of_range() {
+        let opts = CompressOptions {
+            max_command_len: MAX_COMMAND_LEN + 1,
+            ..Default::default()
+        };
+        compress_with(&[1, 2, 3], &opts);
+    }
+
+    #[test]
+    #[should_panic(expected = "chunk address overflow")]
+    fn test_compress_into_chunks_at_overflow() {
+        // Only 11 bytes are left before the end of the address space.
+        compress_into_chunks_at(&random_bytes(100, 1), usize::MAX - 10, 0x8000);
+    }
+
+    #[test]
+    fn test_decompress_prefix() {
+        let src = include_bytes!("green_brinstar_main_shaft.bin");
+        let data = decompress(src).unwrap();
+        for len in [0, 1, 256, 0x1234, data.len(), data.len() + 1] {
+            let prefix = decompress_prefix(src, len).unwrap();
+            assert_eq!(prefix, data[..len.min(data.len())]);
+        }
+
+        // Nothing after the cutoff is decoded.
+        let stream = [0x23, 0xAA, 0x02, 1, 2, 3, 0x81, 0x20];
+        assert_eq!(
+            decompress_prefix(&stream, 5),
+            Ok(vec![0xAA, 0xAA, 0xAA, 0xAA, 1])
+        );
+        assert_eq!(decompress_prefix(&stream, 7).map(|d| d.len()), Ok(7));
+        assert_eq!(
+            decompress_prefix(&stream, 8),
+            Err(DecompressionError::UnexpectedEof { offset: 8 })
+        );
+    }
+
+    #[test]
+    fn test_decompress_all() {
+        let src = include_bytes!("green_brinstar_main_shaft.bin");
+        let mut stream = compress(&[1, 2, 3]);
+        stream.extend_from_slice(src);
+        stream.push(0xFF);
+        assert_eq!(
+            decompress_all(&stream),
+            Ok(vec![vec![1, 2, 3], decompress(src).unwrap(), vec![]])
+        );
+        assert_eq!(decompress_all(&[]), Ok(vec![]));
+
+        assert_eq!(
+            decompress_all(&[0x23, 0xAA, 0xFF, 0x02, 1, 2]),
+            Err(DecompressionError::UnexpectedEof { offset: 6 })
+        );
+        assert_eq!(
+            decompress_all(&[0x23, 0xAA, 0xFF, 0x00, 1]),
+            Err(DecompressionError::MissingStop { offset: 5 })
+        );
+    }
+
+    #[test]
+    fn test_block_length() {
+        let src = include_bytes!("green_brinstar_main_shaft.bin");
+        let mut rom = vec![0x12, 0x34];
+        rom.extend_from_slice(src);
+        rom.extend_from_slice(&[0x23, 0xAA, 0xFF]);
+        assert_eq!(block_length(&rom, 2), Ok(src.len()));
+        assert_eq!(block_length(&rom, 2 + src.len()), Ok(3));
+        assert_eq!(block_length(&rom, rom.len() - 1), Ok(1));
+        assert_eq!(
+            block_length(&rom, rom.len() + 1),
+            Err(DecompressionError::UnexpectedEof { offset: rom.len() })
+        );
+        assert_eq!(
+            block_length(&[0x00, 0xC0, 0x01, 0xFF], 1),
+            Err(DecompressionError::RelativeWindowOutOfRange {
+                offset: 1,
+                distance: 1,
+                output_len: 0
+            })
+        );
+    }
+
+    #[test]
+    fn test_try_compress() {
+        let data = decompress(include_bytes!("green_brinstar_main_shaft.bin")).unwrap();
+        assert_eq!(try_compress(&data), Ok(compress(&data)));
+        assert_eq!(try_compress(&[]), Ok(vec![0xFF]));
+
+        let data = vec![0; MAX_INPUT_LEN];
+        assert_eq!(try_compress(&data), Ok(compress(&data)));
+        let data = vec![0; MAX_INPUT_LEN + 1];
+        assert_eq!(
+            try_compress(&data),
+            Err(CompressError::InputTooLarge {
+                len: MAX_INPUT_LEN + 1
+            })
+        );
+        // compress still accepts it
+        assert_eq!(decompress(&compress(&data)).unwrap(), data);
+    }
+
+    #[test]
+    fn test_compress_within() {
+        let data = decompress(include_bytes!("green_brinstar_main_shaft.bin")).unwrap();
+        let data = &data[..0x800];
+        let default = compress(data);
+        let optimal = compress_optimal(data);
+        assert!(optimal.len() < default.len());
+
+        assert_eq!(compress_within(data, default.len()), Ok(default));
+        assert_eq!(compress_within(data, optimal.len()), Ok(optimal.clone()));
+        assert_eq!(
+            compress_within(data, optimal.len() - 5),
+            Err(CompressError::OverBudget {
+                size: optimal.len(),
+                budget: optimal.len() - 5
+            })
+        );
+    }
+
+    #[test]
+    fn test_compress_into_chunks() {
+        let data = decompress(include_bytes!("green_brinstar_main_shaft.bin")).unwrap();
+        let data = &data[..0x4000];
+        let chunks = compress_into_chunks(data, 0x100);
+        assert!(chunks.len() > 1);
+        let mut output = Vec::new();
+        for chunk in &chunks {
+            assert!(chunk.len() <= 0x100);
+            output.extend(decompress(chunk).unwrap());
+        }
+        assert_eq!(output, data);
+
+        assert_eq!(compress_into_chunks(data, 0x10000), [compress(data)]);
+        assert_eq!(compress_into_chunks(&[], 3), Vec::<Vec<u8>>::new());
+        assert_eq!(
+            compress_into_chunks(&[1, 2, 3], 3),
+            [[0x00, 1, 0xFF], [0x00, 2, 0xFF], [0x00, 3, 0xFF]]
+        );
+    }
+
+    #[test]
+    fn test_compress_into_chunks_at() {
+        let data = decompress(include_bytes!("green_brinstar_main_shaft.bin")).unwrap();
+        let data = &data[..0x1000];
+        let chunks = compress_into_chunks_at(data, 0x80F0, 0x100);
+        assert_eq!(chunks[0].0, 0x80F0);
+        assert!(chunks[0].1.len() <= 0x10);
+        let mut output = Vec::new();
+        let mut next_addr = 0x80F0;
+        for (addr, chunk) in &chunks {
+            assert!(*addr >= next_addr);
+            assert_eq!(addr / 0x100, (addr + chunk.len() - 1) / 0x100);
+            next_addr = addr + chunk.len();
+            output.extend(decompress(chunk).unwrap());
+        }
+        assert_eq!(output, data);
+
+        // Two bytes can't hold a chunk, so it starts in the next bank
+        assert_eq!(
+            compress_into_chunks_at(&[1, 2, 3], 0x7FFE, 0x8000),
+            [(0x8000, compress(&[1, 2, 3]))]
+        );
+        assert_eq!(compress_into_chunks_at(&[], 0, 3), []);
+    }
+
+    #[test]
+    fn test_decompressor() {
+        let mut decompressor = Decompressor::new(&[0x2, 1, 2, 3, 0xC5, 0x03, 0xFF, 0x12]);
+        assert_eq!(decompressor.step(), Some(Ok(&[1, 2, 3][..])));
+        assert_eq!(decompressor.step(), Some(Ok(&[1, 2, 3, 1, 2, 3][..])));
+        assert!(!decompressor.is_done());
+        assert_eq!(decompressor.step(), None);
+        assert!(decompressor.is_done());
+        assert_eq!(decompressor.step(), None);
+        assert_eq!(decompressor.consumed(), 7);
+        assert_eq!(decompressor.output(), [1, 2, 3, 1, 2, 3, 1, 2, 3]);
+
+        let src = include_bytes!("green_brinstar_main_shaft.bin");
+        let mut decompressor = Decompressor::new(src);
+        let mut steps = 0;
+        while let Some(output) = decompressor.step() {
+            output.unwrap();
+            steps += 1;
+        }
+        assert_eq!(steps, disassemble(src).unwrap().len() - 1);
+        assert_eq!(decompressor.into_output(), decompress(src).unwrap());
+
+        let mut decompressor = Decompressor::new(&[0x23, 0xAA, 0xC1]);
+        assert_eq!(decompressor.step(), Some(Ok(&[0xAA; 4][..])));
+        assert_eq!(
+            decompressor.step(),
+            Some(Err(DecompressionError::UnexpectedEof { offset: 3 }))
+        );
+        assert_eq!(decompressor.step(), None);
+    }
+
+    #[test]
+    fn test_decompress_iter() {
+        let src = include_bytes!("green_brinstar_main_shaft.bin");
+        let data = decompress_iter(src).collect::<Result<Vec<_>, _>>();
+        assert_eq!(data, decompress(src));
+        assert_eq!(
+            decompress_iter(src)
+                .skip(100)
+                .take(3)
+                .collect::<Result<Vec<_>, _>>(),
+            Ok(decompress(src).unwrap()[100..103].to_vec())
+        );
+        assert_eq!(decompress_iter(&[0xFF]).count(), 0);
+
+        assert_eq!(
+            decompress_iter(&[0x21, 0xAA, 0xC1]).collect::<Vec<_>>(),
+            [
+                Ok(0xAA),
+                Ok(0xAA),
+                Err(DecompressionError::UnexpectedEof { offset: 3 })
+            ]
+        );
+    }
+
+    #[test]
+    fn test_emit_stop() {
+        let data = decompress(include_bytes!("green_brinstar_main_shaft.bin")).unwrap();
+        let opts = CompressOptions {
+            emit_stop: false,
+            verify: true,
+            ..Default::default()
+        };
+        let compressed = compress_with(&data, &opts);
+        let terminated = compress(&data);
+        assert_eq!(compressed, terminated[..terminated.len() - 1]);
+        assert_eq!(decompress_unterminated(&compressed), Ok(data.clone()));
+        assert_eq!(decompress_unterminated(&terminated), Ok(data.clone()));
+        assert_eq!(
+            Compressor::with_options(opts.clone()).compress(&data),
+            compressed
+        );
+
+        // Several unterminated blocks, ended by one stop command.
+        let mut container = compress_with(&[1, 2, 3], &opts);
+        container.extend(compress_with(&[4; 8], &opts));
+        container.push(0xFF);
+        container.push(0x12);
+        let mut expected = vec![1, 2, 3];
+        expected.extend([4; 8]);
+        assert_eq!(decompress_unterminated(&container), Ok(expected.clone()));
+        assert_eq!(decompress(&container), Ok(expected));
+
+        assert!(compress_with(&[], &opts).is_empty());
+        assert_eq!(decompress_unterminated(&[]), Ok(vec![]));
+        assert_eq!(
+            decompress_unterminated(&[0x02, 1, 2]),
+            Err(DecompressionError::UnexpectedEof { offset: 3 })
+        );
+    }
+
+    #[test]
+    fn test_preprocess() {
+        use preprocess::*;
+
+        let tile = (0..16).collect::<Vec<u8>>();
+        assert_eq!(
+            deinterleave_2bpp(&tile),
+            [0, 2, 4, 6, 8, 10, 12, 14, 1, 3, 5, 7, 9, 11, 13, 15]
+        );
+        let tile = (0..32).collect::<Vec<u8>>();
+        assert_eq!(
+            deinterleave_4bpp(&tile)[8..24],
+            [1, 3, 5, 7, 9, 11, 13, 15, 16, 18, 20, 22, 24, 26, 28, 30]
+        );
+
+        // Round trips, including a partial tile at the end.
+        let data = random_bytes(0x1010, 5);
+        assert_eq!(interleave_2bpp(&deinterleave_2bpp(&data)), data);
+        assert_eq!(interleave_4bpp(&deinterleave_4bpp(&data)), data);
+        assert_eq!(deinterleave_4bpp(&data)[0x1000..], data[0x1000..]);
+
+        let compressed = compress(&deinterleave_2bpp(&data));
+        assert_eq!(interleave_2bpp(&decompress(&compressed).unwrap()), data);
+
+        assert_eq!(
+            delta_encode_words(&[0x00, 0x80, 0x10, 0x80, 0x00, 0x00, 0x07]),
+            [0x00, 0x80, 0x10, 0x00, 0xF0, 0x7F, 0x07]
+        );
+        assert_eq!(delta_decode_words(&delta_encode_words(&data)), data);
+        assert_eq!(
+            delta_decode_words(&delta_encode_words(&data[1..])),
+            data[1..]
+        );
+
+        // A pointer table with a constant stride, crossing a bank boundary.
+        let table = (0..0x200u16)
+            .flat_map(|i| (0xFF00u16.wrapping_add(i * 0x12)).to_le_bytes())
+            .collect::<Vec<u8>>();
+        let compressed = compress(&delta_encode_words(&table));
+        assert!(compressed.len() < compress(&table).len() / 4);
+        assert_eq!(delta_decode_words(&decompress(&compressed).unwrap()), table);
+
+        assert_eq!(reverse_bytes(&[1, 2, 3]), [3, 2, 1]);
+        let compressed = compress(&reverse_bytes(&data));
+        assert_eq!(reverse_bytes(&decompress(&compressed).unwrap()), data);
+    }
+
+    #[test]
+    fn test_encoded_len() {
+        let src = include_bytes!("green_brinstar_main_shaft.bin");
+        let canonical = canonicalize(src).unwrap();
+        let cmds = disassemble(&canonical).unwrap();
+        for window in cmds.windows(2) {
+            let (offset, cmd) = &window[0];
+            assert_eq!(cmd.encoded_len(), window[1].0 - offset);
+            assert_eq!(
+                assemble(core::slice::from_ref(cmd)).unwrap().len(),
+                cmd.encoded_len() + 1
+            );
+        }
+        assert_eq!(
+            cmds.iter().map(|(_, cmd)| cmd.len()).sum::<usize>(),
+            decompressed_size(src).unwrap()
+        );
+
+        let inverted = Command::Backreference {
+            src: Reference::Relative(1),
+            invert: true,
+            len: 4,
+        };
+        assert_eq!(inverted.encoded_len(), 3);
+        assert_eq!(Command::ByteFill { data: 0, len: 32 }.encoded_len(), 2);
+        assert_eq!(Command::ByteFill { data: 0, len: 33 }.encoded_len(), 3);
+        assert_eq!(Command::Stop.encoded_len(), 1);
+    }
+
+    #[test]
+    fn test_large_repetitive_input() {
+        // Each of these would take a full search (and a scan to the end of the input) at every
+        // command without reusing the previous command.
+        for pattern in [&[0x12, 0x34, 0x56][..], &[0xAA], &[0x55, 0xAA], &[7; 7]] {
+            let data = pattern
+                .iter()
+                .copied()
+                .cycle()
+                .take(1 << 20)
+                .collect::<Vec<u8>>();
+            let compressed = compress(&data);
+            assert!(compressed.len() <= 4 * (data.len() / MAX_COMMAND_LEN) + 1);
+            assert_eq!(decompress(&compressed).unwrap(), data);
+        }
+
+        let data = (0..1 << 20).map(|i| i as u8).collect::<Vec<u8>>();
+        let compressed = compress(&data);
+        assert_eq!(compressed.len(), 3 * (1 << 10) + 1);
+        assert_eq!(decompress(&compressed).unwrap(), data);
+    }
+
+    #[test]
+    fn test_allowed_commands() {
+        let mut data = decompress(include_bytes!("green_brinstar_main_shaft.bin")).unwrap();
+        data.extend(0..=0xFF);
+        data.extend(data[..0x100].iter().map(|x| !x).collect::<Vec<u8>>());
+
+        let all = disassemble(&compress(&data)).unwrap();
+        assert!(all
+            .iter()
+            .any(|(_, cmd)| matches!(cmd, OwnedCommand::Incrementing { .. })));
+
+        for allowed in [
+            CommandSet::all().without(CommandSet::INCREMENTING),
+            CommandSet::copy_and_fill_only(),
+            CommandSet::copy_only(),
+            CommandSet::RELATIVE | CommandSet::INVERTED_ABSOLUTE,
+        ] {
+            for mode in [CompressMode::DecodeSpeed, CompressMode::SizeOptimal] {
+                let opts = CompressOptions {
+                    allowed_commands: allowed,
+                    ..mode.into()
+                };
+                let compressed = compress_with(&data, &opts);
+                assert_eq!(decompress(&compressed).unwrap(), data);
+                for (_, cmd) in disassemble(&compressed).unwrap() {
+                    assert!(allowed.allows(&cmd.as_command()), "{cmd} is not allowed");
+                }
+            }
+        }
+
+        let opts = CompressOptions {
+            allowed_commands: CommandSet::all().without(CommandSet::INCREMENTING),
+            ..Default::default()
+        };
+        let compressed = compress_with(&data, &opts);
+        for (offset, _) in disassemble(&compressed).unwrap() {
+            let header = compressed[offset];
+            let ty = if header >> 5 == 7 {
+                (header >> 2) & 7
+            } else {
+                header >> 5
+            };
+            assert_ne!(ty, 3);
+        }
+
+        assert!(CommandSet::all().contains(CommandSet::copy_and_fill_only()));
+        assert!(!CommandSet::copy_only().allows(&Command::ByteFill { data: 0, len: 1 }));
+        assert!(CommandSet::copy_only().allows(&Command::Copy(&[0])));
+    }
+
+    #[test]
+    fn test_compress_if_smaller() {
+        let data = decompress(include_bytes!("green_brinstar_main_shaft.bin")).unwrap();
+        assert_eq!(compress_if_smaller(&data), Some(compress(&data)));
+
+        assert_eq!(compress_if_smaller(&random_bytes(0x100, 4)), None);
+        assert_eq!(compress_if_smaller(&[]), None);
+        assert_eq!(compress_if_smaller(&[0; 3]), None);
+        assert_eq!(compress_if_smaller(&[0; 8]), Some(vec![0x27, 0x00, 0xFF]));
+    }
+
+    #[test]
+    fn test_compress_or_store() {
+        let data = decompress(include_bytes!("green_brinstar_main_shaft.bin")).unwrap();
+        assert_eq!(compress_or_store(&data), (true, compress(&data)));
+
+        for len in [0, 1, 32, 33, 0x400, 0x1234] {
+            let data = random_bytes(len, 9);
+            let (compressed, stored) = compress_or_store(&data);
+            assert!(!compressed);
+            assert_eq!(stored.len(), compress_bound(len));
+            assert!(disassemble(&stored)
+                .unwrap()
+                .iter()
+                .all(|(_, cmd)| matches!(cmd, OwnedCommand::Copy(_) | OwnedCommand::Stop)));
+            assert_eq!(decompress(&stored).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn test_word_fill_phase() {
+        // An alternating run after an odd number of literals is a single fill, whichever byte it
+        // starts with.
+        for data in [
+            &[9, 4, 7, 0xAA, 0xBB, 0xAA, 0xBB, 0xAA, 0xBB, 0xAA][..],
+            &[5, 1, 0xBB, 0xAA, 0xBB, 0xAA, 0xBB, 0xAA, 0xBB, 0xAA, 0xBB],
+        ] {
+            let compressed = compress_with_mode(data, CompressMode::SizeOptimal);
+            let cmds = disassemble(&compressed).unwrap();
+            let (_, OwnedCommand::Copy(literals)) = &cmds[0] else {
+                panic!("{cmds:?}");
+            };
+            assert_eq!(
+                cmds[1].1,
+                OwnedCommand::WordFill {
+                    data: u16::from_le_bytes([data[literals.len()], data[literals.len() + 1]]),
+                    len: data.len() - literals.len()
+                }
+            );
+            assert_eq!(decompress(&compressed).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn test_word_fill_tail() {
+        let words = |n: usize| [0x12, 0x34].repeat(n);
+
+        // A partial last word is the low byte, and anything else ends the fill
+        assert_eq!(compress(&words(5)), [0x49, 0x12, 0x34, 0xFF]);
+        assert_eq!(
+            compress(&[words(5), vec![0x12]].concat()),
+            [0x4A, 0x12, 0x34, 0xFF]
+        );
+        assert_eq!(
+            compress(&[words(5), vec![0x34]].concat()),
+            [0x49, 0x12, 0x34, 0x00, 0x34, 0xFF]
+        );
+        assert_eq!(
+            compress(&[words(5), vec![0x12, 0x12]].concat()),
+            [0x4A, 0x12, 0x34, 0x00, 0x12, 0xFF]
+        );
+
+        // The tail doesn't push a fill past the length limit
+        assert_eq!(
+            compress(&[words(0x200), vec![0x12]].concat()),
+            [0xEB, 0xFF, 0x12, 0x34, 0x00, 0x12, 0xFF]
+        );
+        let opts = CompressOptions {
+            max_command_len: 0x21,
+            ..Default::default()
+        };
+        assert_eq!(
+            compress_with(&words(0x20), &opts),
+            [0xE8, 0x20, 0x12, 0x34, 0x5E, 0x34, 0x12, 0xFF]
+        );
+        // After an odd-length fill, the next one starts on the high byte
+        assert_eq!(
+            compress_with(&words(0x21), &opts),
+            [0xE8, 0x20, 0x12, 0x34, 0xE8, 0x20, 0x34, 0x12, 0xFF]
+        );
+    }
+
+    #[test]
+    fn test_run_as_relative_backreference() {
+        // A model that only accepts a run as a backreference to the byte just before it
+        let distance_one = |cmd: &Command| match cmd {
+            Command::Backreference {
+                src: Reference::Relative(1),
+                invert: false,
+                len: _,
+            } => 2,
+            Command::Copy(buf) => buf.len() + 1,
+            _ => 1000,
+        };
+
+        // The second run also matches the first one, at a distance of 41
+        let mut data = vec![7; 40];
+        data.push(1);
+        data.extend([7; 40]);
+        let compressed =
+            compress_with_cost_model(&data, &CompressOptions::default(), &distance_one);
+        assert_eq!(decompress(&compressed).unwrap(), data);
+        assert_eq!(
+            disassemble(&compressed)
+                .unwrap()
+                .into_iter()
+                .map(|(_, cmd)| cmd)
+                .collect::<Vec<_>>(),
+            [
+                OwnedCommand::Copy(vec![7]),
+                OwnedCommand::Backreference {
+                    src: Reference::Relative(1),
+                    invert: false,
+                    len: 39
+                },
+                OwnedCommand::Copy(vec![1, 7]),
+                OwnedCommand::Backreference {
+                    src: Reference::Relative(1),
+                    invert: false,
+                    len: 39
+                },
+                OwnedCommand::Stop,
+            ]
+        );
+
+        // The built-in models still prefer a byte fill
+        assert_eq!(
+            compress(&[3, 7, 7, 7, 7, 7, 7, 7, 7]),
+            [0x00, 3, 0x27, 7, 0xFF]
+        );
+    }
+
+    #[test]
+    fn test_backreference_tie_breaking() {
+        let opts = CompressOptions::from(CompressMode::SizeOptimal);
+        let pattern = [0x10, 0x32, 0x54, 0x76, 0x98, 0xBA];
+        let inverted = pattern.map(|x| !x);
+
+        // Nearby repeats use relative backreferences, which are smaller
+        let data = [pattern, pattern].concat();
+        assert_eq!(
+            compress_with(&data, &opts)[7..],
+            [0xC5, 0x06, 0xFF] // relative
+        );
+        let data = [pattern, inverted].concat();
+        assert_eq!(
+            compress_with(&data, &opts)[7..],
+            [0xFC, 0x05, 0x06, 0xFF] // inverted relative
+        );
+
+        // Repeats too far apart for a relative backreference
+        let filler = random_bytes(0x120, 5);
+        let data = [&pattern[..], &filler, &pattern].concat();
+        let compressed = compress_with(&data, &opts);
+        assert_eq!(decompress(&compressed).unwrap(), data);
+        assert_eq!(
+            compressed[compressed.len() - 4..],
+            [0x85, 0x00, 0x00, 0xFF] // absolute
+        );
+        let data = [&pattern[..], &filler, &inverted].concat();
+        let compressed = compress_with(&data, &opts);
+        assert_eq!(decompress(&compressed).unwrap(), data);
+        assert_eq!(
+            compressed[compressed.len() - 4..],
+            [0xA5, 0x00, 0x00, 0xFF] // inverted absolute
+        );
+
+        // Ties go to the cheapest backreference under the cost model in use. Here the last
+        // pattern matches both the first one as-is and the second one inverted.
+        let data = [&pattern[..], &[0], &inverted, &[0], &pattern].concat();
+        let compressed = compress_with(&data, &opts);
+        assert_eq!(compressed[compressed.len() - 3..], [0xC5, 0x0E, 0xFF]);
+        let expensive_as_is = |cmd: &Command| match cmd {
+            Command::Backreference { invert: false, .. } => 10,
+            _ => SizeCostModel.cost(cmd),
+        };
+        let compressed = compress_with_cost_model(&data, &opts, &expensive_as_is);
+        assert_eq!(decompress(&compressed).unwrap(), data);
+        assert_eq!(compressed[compressed.len() - 4..], [0xFC, 0x05, 0x07, 0xFF]);
+    }
+
+    #[test]
+    fn test_commands() {
+        let cmds = commands(&[0x2, 1, 2, 3, 0xC5, 0x03, 0xFF, 0x00]).collect::<Vec<_>>();
+        assert_eq!(
+            cmds,
+            [
+                Ok(Command::Copy(&[1, 2, 3])),
+                Ok(Command::Backreference {
+                    src: Reference::Relative(3),
+                    invert: false,
+                    len: 6
+                }),
+                Ok(Command::Stop),
+            ]
+        );
+
+        let mut cmds = commands(&[0x23]);
+        assert_eq!(
+            cmds.next(),
+            Some(Err(DecompressionError::UnexpectedEof { offset: 1 }))
+        );
+        assert_eq!(cmds.next(), None);
+    }
+
+    #[test]
+    fn test_disassemble() {
+        assert_eq!(
+            disassemble(&[0x2, 1, 2, 3, 0xFC, 0x5, 0x03, 0x23, 0xAA, 0xFF]),
+            Ok(vec![
+                (0, OwnedCommand::Copy(vec![1, 2, 3])),
+                (
+                    4,
+                    OwnedCommand::Backreference {
+                        src: Reference::Relative(3),
+                        invert: true,
+                        len: 6
+                    }
+                ),
+                (7, OwnedCommand::ByteFill { data: 0xAA, len: 4 }),
+                (9, OwnedCommand::Stop),
+            ])
+        );
+        assert_eq!(
+            disassemble(&[0x2, 1]),
+            Err(DecompressionError::UnexpectedEof { offset: 2 })
+        );
+    }
+
+    #[test]
+    fn test_compress_produces_valid_commands() {
+        // Long literal runs, maximum-length fills and long inverted matches all have to be split.
+        let mut data = random_bytes(0x1234, 11);
+        data.extend(core::iter::repeat_n(0x55, 0x900));
+        let inverted = data[0x100..0x900].iter().map(|x| !x).collect::<Vec<u8>>();
+        data.extend_from_slice(&inverted);
+        data.extend((0..0x900).map(|i| i as u8));
+
+        for mode in [CompressMode::DecodeSpeed, CompressMode::SizeOptimal] {
+            let compressed = compress_with_mode(&data, mode);
+            let cmds = disassemble(&compressed)
+                .unwrap()
+                .into_iter()
+                .map(|(_, cmd)| cmd)
+                .collect::<Vec<_>>();
+            assert_eq!(assemble(&cmds), Ok(compressed));
+        }
+    }
+
+    #[test]
+    fn test_rebase_absolute() {
+        // 4 literal bytes, then an absolute backreference to 2 and a relative one
+        let stream = [0x03, 1, 2, 3, 4, 0x81, 0x02, 0x00, 0xC1, 0x02, 0xFF];
+        let rebased = rebase_absolute(&stream, 0x1000).unwrap();
+        assert_eq!(
+            rebased,
+            [0x03, 1, 2, 3, 4, 0x81, 0x02, 0x10, 0xC1, 0x02, 0xFF]
+        );
+        assert_eq!(rebase_absolute(&rebased, -0x1000), Ok(stream.to_vec()));
+        assert_eq!(rebase_absolute(&stream, 0), Ok(stream.to_vec()));
+
+        // Moving the block after a prefix keeps it decodable against that prefix.
+        let prefix = [9; 0x20];
+        let shifted = rebase_absolute(&stream, prefix.len() as i32).unwrap();
+        assert_eq!(
+            decompress_with_prefix(&shifted, &prefix).unwrap(),
+            decompress(&stream).unwrap()
+        );
+
+        assert_eq!(
+            rebase_absolute(&stream, -3),
+            Err(DecompressionError::RebaseOutOfRange {
+                offset: 5,
+                address: -1
+            })
+        );
+        assert_eq!(
+            rebase_absolute(&stream, 0xFFFE),
+            Err(DecompressionError::RebaseOutOfRange {
+                offset: 5,
+                address: 0x10000
+            })
+        );
+        assert!(rebase_absolute(&[0x81, 0x02], 0).is_err());
+        assert_eq!(
+            rebase_absolute(&[0x00, 1, 0xC0, 0x00, 0xFF], 0),
+            Err(DecompressionError::RelativeZeroOffset { offset: 2 })
+        );
+    }
+
+    #[test]
+    fn test_assemble() {
+        let stream = [0x2, 1, 2, 3, 0xFC, 0x5, 0x03, 0x23, 0xAA, 0xFF];
+        let cmds = disassemble(&stream)
+            .unwrap()
+            .into_iter()
+            .map(|(_, cmd)| cmd)
+            .collect::<Vec<_>>();
+        assert_eq!(assemble(&cmds), Ok(stream.to_vec()));
+        assert_eq!(assemble(&cmds[..3]), Ok(stream.to_vec()));
+
+        assert_eq!(
+            assemble(&[OwnedCommand::Copy(vec![0; 0x401])]),
+            Err(CompressError::CommandTooLong { index: 0 })
+        );
+        assert_eq!(
+            assemble(&[
+                OwnedCommand::Copy(vec![1]),
+                OwnedCommand::Backreference {
+                    src: Reference::Relative(1),
+                    invert: true,
+                    len: 0x301
+                }
+            ]),
+            Err(CompressError::InvertedRelativeTooLong { index: 1 })
+        );
+        assert_eq!(
+            assemble(&[OwnedCommand::Backreference {
+                src: Reference::Relative(0),
+                invert: false,
+                len: 4
+            }]),
+            Err(CompressError::InvalidRelativeOffset { index: 0 })
+        );
+        assert_eq!(
+            assemble(&[OwnedCommand::Copy(vec![1]), OwnedCommand::Copy(vec![])]),
+            Err(CompressError::EmptyCommand { index: 1 })
+        );
+        assert_eq!(
+            assemble(&[OwnedCommand::ByteFill { data: 0, len: 0 }]),
+            Err(CompressError::EmptyCommand { index: 0 })
+        );
+        assert_eq!(assemble(&[OwnedCommand::Stop]), Ok(vec![0xFF]));
+        assert_eq!(
+            assemble(&[
+                OwnedCommand::Copy(vec![1]),
+                OwnedCommand::Stop,
+                OwnedCommand::Copy(vec![2]),
+            ]),
+            Err(CompressError::EarlyStop { index: 1 })
+        );
+        assert_eq!(
+            assemble(&[OwnedCommand::Stop, OwnedCommand::Stop]),
+            Err(CompressError::EarlyStop { index: 0 })
+        );
+    }
+
+    #[test]
+    fn test_limits() {
+        let inverted = OwnedCommand::Backreference {
+            src: Reference::Relative(1),
+            invert: true,
+            len: MAX_INVERTED_RELATIVE_LEN,
+        };
+        let stream = assemble(&[OwnedCommand::Copy(vec![1]), inverted]).unwrap();
+        assert_eq!(stream[2..], [0xFE, 0xFF, 0x01, 0xFF]);
+
+        let copy = OwnedCommand::Copy(vec![0; MAX_COMMAND_LEN]);
+        assert_eq!(assemble(&[copy]).unwrap()[..2], [0xE3, 0xFF]);
+
+        let absolute = OwnedCommand::Backreference {
+            src: Reference::Absolute(MAX_ABSOLUTE_OFFSET as u16),
+            invert: false,
+            len: 1,
+        };
+        assert_eq!(assemble(&[absolute]), Ok(vec![0x80, 0xFF, 0xFF, 0xFF]));
+
+        let mut stream = vec![0xE0, MAX_RELATIVE_DISTANCE as u8 - 1];
+        stream.extend(0..MAX_RELATIVE_DISTANCE as u8);
+        stream.extend([0xC0, MAX_RELATIVE_DISTANCE as u8, 0xFF]);
+        assert_eq!(decompress(&stream).unwrap()[MAX_RELATIVE_DISTANCE], 0);
+    }
+
+    #[test]
+    fn test_decompress_bounded() {
+        let stream = [0x2, 1, 2, 3, 0xFB, 0xFE, 0x3, 0xFF];
+        assert_eq!(decompress_bounded(&stream, 1026).map(|d| d.len()), Ok(1026));
+        assert_eq!(
+            decompress_bounded(&stream, 1025),
+            Err(DecompressionError::OutputTooLarge { offset: 4 })
+        );
+        assert_eq!(
+            decompress_bounded(&stream, 2),
+            Err(DecompressionError::OutputTooLarge { offset: 0 })
+        );
+    }
+
+    #[test]
+    fn test_compress_options() {
+        // By default, short runs aren't worth breaking up a copy for
+        assert_eq!(compress(&[1, 1, 1, 1]), vec![0x03, 1, 1, 1, 1, 0xFF]);
+        assert_eq!(compress(&[1; 8]), vec![0x27, 1, 0xFF]);
+
+        let opts = CompressOptions {
+            min_savings: 0,
+            ..Default::default()
+        };
+        assert_eq!(compress_with(&[1, 1, 1, 1], &opts), vec![0x23, 1, 0xFF]);
+
+        // With the decode speed tweaks, a backreference must be long to win over a copy
+        let opts = CompressOptions {
+            min_savings: 1,
+            ..Default::default()
+        };
+        let data = [9, 8, 7, 1, 9, 8, 7, 1];
+        assert_eq!(
+            compress_with(&data, &opts),
+            [0x07, 9, 8, 7, 1, 9, 8, 7, 1, 0xFF]
+        );
+        let opts = CompressOptions {
+            mode: CompressMode::SizeOptimal,
+            ..opts
+        };
+        assert_eq!(
+            compress_with(&data, &opts),
+            [0x03, 9, 8, 7, 1, 0xC3, 4, 0xFF]
+        );
+    }
+
+    #[test]
+    fn test_compress() {
+        // These tests exercise the size-optimized settings, without the decode speed tweaks.
+        let compress = |src: &[u8]| compress_with_mode(src, CompressMode::SizeOptimal);
+
+        assert_eq!(compress(&[0, 2, 4, 6]), vec![0x03, 0, 2, 4, 6, 0xFF]);
+        assert_eq!(compress(&[1, 1, 1, 1]), vec![0x23, 1, 0xFF]);
+        assert_eq!(compress(&[1, 2, 1, 2, 1, 2]), vec![0x45, 1, 2, 0xFF]);
+        assert_eq!(compress(&[1, 2, 1, 2, 1]), vec![0x44, 1, 2, 0xFF]);
+        assert_eq!(compress(&[1, 2, 3, 4]), vec![0x63, 1, 0xFF]);
+
+        assert_eq!(
+            compress(&[1, 2, 3, 4, 1, 2, 3, 4, 1, 2, 3, 4]),
+            vec![0x63, 1, 0xC7, 0x4, 0xFF]
+        );
+
+        // a relative inverted backreference can only be encoded as an extended command
+        assert_eq!(
+            compress(&[1, 2, 3, 4, !1, !2, !3, !4, 1, 2, 3, 4]),
+            vec![0x63, 1, 0xFC, 0x07, 0x4, 0xFF]
+        );
+
+        // a relative inverted backreference must not collide with the stop symbol
+        let tricky = [1, 2, 3, 4]
+            .into_iter()
+            .chain([!1, !2, !3, !4, 1, 2, 3, 4].into_iter().cycle().take(0x400))
+            .collect::<Vec<u8>>();
+        assert_eq!(
+            compress(&tricky),
+            [0x63, 1, 0xFE, 0xFF, 0x4, 0xF8, 0xFF, 0xF8, 0xFF]
+        );
+
+        // create a 512-byte-long non-compressible sequence
+        let seq = || {
+            std::iter::successors(Some(1u8), |&x| Some(x.wrapping_add(3)))
+                .take(256)
+                .flat_map(|i| [i, i.wrapping_sub(1)])
+        };
+        assert_eq!(
+            compress(&seq().chain(seq()).collect::<Vec<u8>>()),
+            [0xE1, 0xFF]
+                .into_iter()
+                .chain(seq())
+                .chain([0xF1, 0xFF, 0x00, 0x00, 0xFF])
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_compress_into() {
+        let mut dst = vec![0xAA];
+        compress_into(&[1, 2, 3, 4, 1, 2, 3, 4], &mut dst);
+        compress_into(&[5, 6, 7, 8, 5, 6, 7, 8], &mut dst);
+        assert_eq!(dst[0], 0xAA);
+
+        let first = compress(&[1, 2, 3, 4, 1, 2, 3, 4]);
+        assert_eq!(&dst[1..][..first.len()], &first);
+        assert_eq!(
+            decompress(&dst[1 + first.len()..]),
+            Ok(vec![5, 6, 7, 8, 5, 6, 7, 8])
+        );
+    }
+
+    #[test]
+    fn test_cost_model() {
+        let data = [9, 8, 7, 1, 9, 8, 7, 1];
+        let opts = CompressOptions::from(CompressMode::SizeOptimal);
+        assert_eq!(
+            compress_with_cost_model(&data, &opts, &SizeCostModel),
+            compress_with(&data, &opts)
+        );
+
+        // A model that makes backreferences prohibitively expensive
+        let no_backrefs = |cmd: &Command| match cmd {
+            Command::Backreference { .. } => 100,
+            _ => SizeCostModel.cost(cmd),
+        };
+        assert_eq!(
+            compress_with_cost_model(&data, &opts, &no_backrefs),
+            [0x07, 9, 8, 7, 1, 9, 8, 7, 1, 0xFF]
+        );
+        // A literal byte costs 1, and a long fill much less than its length.
+        assert_eq!(SnesCycleCost.cost(&Command::Copy(&[0; 32])), 34);
+        assert_eq!(SnesCycleCost.cost(&Command::Copy(&[0; 31])), 33);
+        // The header, the fill byte, then four bytes written.
+        assert_eq!(
+            SnesCycleCost.cycles(&Command::ByteFill { data: 0, len: 4 }),
+            72 + 26 + 4 * 13
+        );
+        assert!(SnesCycleCost.cost(&Command::ByteFill { data: 0, len: 32 }) < 16);
+        assert!(
+            SnesCycleCost.cycles(&Command::ByteFill { data: 0, len: 33 })
+                > SnesCycleCost.cycles(&Command::ByteFill { data: 0, len: 32 })
+        );
+
+        let data = decompress(include_bytes!("green_brinstar_main_shaft.bin")).unwrap();
+        let opts = CompressOptions {
+            min_savings: 1,
+            ..Default::default()
+        };
+        let snes = compress_with_cost_model(&data, &opts, &SnesCycleCost);
+        assert_eq!(decompress(&snes).unwrap(), data);
+        let cycles = |src: &[u8]| decode_cost_with(src, &|cmd: &Command| SnesCycleCost.cycles(cmd));
+        assert!(cycles(&snes).unwrap() <= cycles(&compress(&data)).unwrap());
+        assert!(
+            cycles(&snes).unwrap()
+                < cycles(&compress_with_mode(&data, CompressMode::SizeOptimal)).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_compress_with_stats() {
+        let data = [
+            1, 2, 3, 4, 1, 2, 3, 4, 9, 9, 9, 9, 9, 9, 9, 9, 9, 1, 2, 3, 4, 5, 6,
+        ];
+        let (compressed, stats) = compress_with_stats(&data);
+        assert_eq!(compressed, compress(&data));
+        assert_eq!(stats.input_len, data.len());
+        assert_eq!(stats.output_len, compressed.len());
+        assert_eq!(
+            stats.byte_fill,
+            CommandStats {
+                count: 1,
+                compressed_bytes: 2,
+                decompressed_bytes: 9
+            }
+        );
+        assert_eq!(stats.copy.count, 1);
+        assert_eq!(stats.copy.decompressed_bytes, 8);
+        assert_eq!(stats.incrementing.decompressed_bytes, 6);
+        assert_eq!(stats.ratio(), compressed.len() as f64 / data.len() as f64);
+        assert_eq!(stats.unreachable_bytes, 0);
+
+        let data = [1, 2, 3].repeat(0x5600);
+        let (_, stats) = compress_with_stats(&data);
+        assert_eq!(stats.unreachable_bytes, 0x200);
+    }
+
+    #[test]
+    fn test_compress_tie_break() {
+        // With every command costing the same, a word fill and a byte fill of the same length tie;
+        // the earlier candidate (the word fill) must win.
+        let opts = CompressOptions {
+            min_savings: 0,
+            ..Default::default()
+        };
+        let flat = |_: &Command| 1;
+        assert_eq!(
+            compress_with_cost_model(&[7, 7], &opts, &flat),
+            [0x41, 7, 7, 0xFF]
+        );
+        assert_eq!(
+            compress_with_cost_model(&[7, 7, 7, 7, 7, 7], &opts, &flat),
+            [0x45, 7, 7, 0xFF]
+        );
+    }
+
+    #[test]
+    fn test_compress_size_optimal() {
+        let data = decompress(include_bytes!("green_brinstar_main_shaft.bin")).unwrap();
+        let fast = compress(&data);
+        let small = compress_with_mode(&data, CompressMode::SizeOptimal);
+
+        assert!(small.len() < fast.len());
+        assert_eq!(decompress(&small).unwrap(), data);
+    }
+
+    /// Returns `len` bytes of deterministic pseudo-random data.
+    fn random_bytes(len: usize, seed: u32) -> Vec<u8> {
+        // xorshift32
+        std::iter::successors(Some(seed), |&x| {
+            let x = x ^ (x << 13);
+            let x = x ^ (x >> 17);
+            Some(x ^ (x << 5))
+        })
+        .skip(1)
+        .take(len)
+        .map(|x| x as u8)
+        .collect()
+    }
+
+    #[test]
+    fn test_roundtrip_incompressible() {
+        let data = random_bytes(4096, 1);
+        let compressed = compress(&data);
+        assert_eq!(decompress(&compressed).unwrap(), data);
+
+        let cmds = disassemble(&compressed).unwrap();
+        assert!(cmds
+            .iter()
+            .all(|(_, cmd)| !matches!(cmd, OwnedCommand::Copy(buf) if buf.len() > 0x400)));
+    }
+
+    #[test]
+    fn test_compress_inverted() {
+        let tile = random_bytes(16, 7);
+        let inverted = tile.iter().map(|x| !x).collect::<Vec<_>>();
+
+        // A nearby inverted copy uses a relative reference (command type 7)
+        let data = [&tile[..], &inverted[..]].concat();
+        let compressed = compress(&data);
+        assert_eq!(
+            &compressed[compressed.len() - 4..],
+            [0xFC, 0x0F, 0x10, 0xFF]
+        );
+        assert_eq!(decompress(&compressed).unwrap(), data);
+
+        // A distant inverted copy uses an absolute reference (command type 5)
+        let data = [&tile[..], &random_bytes(300, 8), &inverted[..]].concat();
+        let compressed = compress(&data);
+        assert_eq!(
+            &compressed[compressed.len() - 4..],
+            [0xAF, 0x00, 0x00, 0xFF]
+        );
+        assert_eq!(decompress(&compressed).unwrap(), data);
+    }
+
+    #[test]
+    fn test_compress_large_repetitive() {
+        // Long-distance repeats are the worst case for the absolute backreference search
+        let block = random_bytes(1000, 3);
+        let data = block
+            .iter()
+            .copied()
+            .cycle()
+            .take(0x10000)
+            .collect::<Vec<_>>();
+        let compressed = compress(&data);
+        assert!(compressed.len() < 1400);
+        assert_eq!(decompress(&compressed).unwrap(), data);
+    }
+
+    #[test]
+    fn test_compress_max_chain() {
+        let data = decompress(include_bytes!("green_brinstar_main_shaft.bin")).unwrap();
+        for max_chain in [0, 1, 4] {
+            let opts = CompressOptions {
+                max_chain,
+                ..Default::default()
+            };
+            let compressed = compress_with(&data, &opts);
+            assert_eq!(decompress(&compressed).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn test_compress_max_distance() {
+        let data = decompress(include_bytes!("green_brinstar_main_shaft.bin")).unwrap();
+        let full = compress(&data);
+        let mut previous = full.len();
+        for max_distance in [0x1000, 0x400, 0x100, 0] {
+            let opts = CompressOptions {
+                max_distance,
+                ..Default::default()
+            };
+            let compressed = compress_with(&data, &opts);
+            assert_eq!(decompress(&compressed).unwrap(), data);
+            assert!(compressed.len() >= previous);
+            previous = compressed.len();
+
+            let mut pos = 0;
+            for (_, cmd) in disassemble(&compressed).unwrap() {
+                if let OwnedCommand::Backreference {
+                    src: Reference::Absolute(start),
+                    ..
+                } = cmd
+                {
+                    assert!(pos - start as usize <= max_distance);
+                }
+                pos += cmd.len();
+            }
+        }
+
+        let opts = CompressOptions {
+            max_distance: 0,
+            ..Default::default()
+        };
+        assert_eq!(
+            compress_with(&data, &opts),
+            compress_with(
+                &data,
+                &CompressOptions {
+                    relative_only: true,
+                    ..Default::default()
+                }
+            )
+        );
+    }
+
+    #[test]
+    fn test_compress_lazy() {
+        let data = decompress(include_bytes!("green_brinstar_main_shaft.bin")).unwrap();
+        let data = &data[..0x2000];
+        let opts = CompressOptions::from(CompressMode::SizeOptimal);
+        let greedy = compress_with(data, &opts);
+        let lazy = compress_with(data, &CompressOptions { lazy: true, ..opts });
+
+        assert!(lazy.len() < greedy.len());
+        assert_eq!(decompress(&lazy).unwrap(), data);
+    }
+
+    #[test]
+    fn test_dictionary() {
+        let dict = random_bytes(0x200, 5);
+        let data = [
+            &dict[0x100..0x180],
+            &random_bytes(0x20, 6),
+            &dict[0x20..0x60],
+        ]
+        .concat();
+
+        let compressed = compress_with_dictionary(&data, &dict);
+        assert!(compressed.len() < compress(&data).len());
+        assert_eq!(
+            decompress_with_dictionary(&compressed, &dict).unwrap(),
+            data
+        );
+
+        assert_eq!(
+            compress_with_dictionary(&dict[..0x40], &dict),
+            [0xF0, 0x3F, 0x00, 0x00, 0xFF]
+        );
+    }
+
+    #[test]
+    fn test_decompress_with_prefix() {
+        let prefix = [1, 2, 3, 4];
+        // absolute addresses index into prefix ++ output
+        assert_eq!(
+            decompress_with_prefix(&[0x83, 0x01, 0x00, 0xFF], &prefix),
+            Ok(vec![2, 3, 4, 2])
+        );
+        // relative offsets count back from the end of prefix ++ output
+        assert_eq!(
+            decompress_with_prefix(&[0x01, 9, 9, 0xC2, 0x04, 0xFF], &prefix),
+            Ok(vec![9, 9, 3, 4, 9])
+        );
+        assert_eq!(
+            decompress_with_prefix(&[0xC0, 0x05, 0xFF], &prefix),
+            Err(DecompressionError::RelativeWindowOutOfRange {
+                offset: 0,
+                distance: 5,
+                output_len: 4
+            })
+        );
+        assert_eq!(
+            decompress_with_prefix(&[0x80, 0x04, 0x00, 0xFF], &prefix),
+            Err(DecompressionError::AbsoluteWindowOutOfRange {
+                offset: 0,
+                start: 4,
+                output_len: 4
+            })
+        );
+    }
+
+    #[test]
+    fn test_compress_optimal() {
+        let data = decompress(include_bytes!("green_brinstar_main_shaft.bin")).unwrap();
+        let data = &data[..0x2000];
+        let greedy = compress_with_mode(data, CompressMode::SizeOptimal);
+        let optimal = compress_optimal(data);
+
+        assert!(optimal.len() < greedy.len());
+        assert_eq!(decompress(&optimal).unwrap(), data);
+
+        assert_eq!(compress_optimal(&[]), [0xFF]);
+        assert_eq!(compress_optimal(&[1, 2, 3, 4]), [0x63, 1, 0xFF]);
+    }
+
+    #[test]
+    fn test_compress_best() {
+        let data = decompress(include_bytes!("green_brinstar_main_shaft.bin")).unwrap();
+        for data in [&data[..0x800], &random_bytes(0x100, 9)] {
+            let best = compress_best(data);
+            assert_eq!(decompress(&best).unwrap(), data);
+            for other in [
+                compress(data),
+                compress_with_mode(data, CompressMode::SizeOptimal),
+                compress_optimal(data),
+            ] {
+                assert!(best.len() <= other.len());
+                if best.len() == other.len() {
+                    assert!(decode_cost(&best).unwrap() <= decode_cost(&other).unwrap());
+                }
+            }
+        }
+
+        assert_eq!(compress_best(&[]), [0xFF]);
+        assert_eq!(compress_best(&[1, 2, 3, 4]), [0x63, 1, 0xFF]);
+    }
+
+    #[test]
+    fn test_find_backreferences() {
+        // At 500, a 16-byte block that appears in full at 0 (out of relative range), and partially
+        // at 320 (6 bytes) and 330 (10 bytes, inverted).
+        let block = random_bytes(16, 21);
+        let mut data = random_bytes(516, 22);
+        data[..16].copy_from_slice(&block);
+        data[320..326].copy_from_slice(&block[..6]);
+        data[326] = !block[6];
+        for k in 0..10 {
+            data[330 + k] = !block[k];
+        }
+        data[340] = block[10];
+        data[500..].copy_from_slice(&block);
+
+        let opts = CompressOptions::default();
+        let found =
+            compress::find_backreferences(&data, 500, &opts, &mut matcher::MatchIndex::new());
+        let backref = |src, invert, len| Some(Command::Backreference { src, invert, len });
+        assert_eq!(
+            found,
+            [
+                backref(Reference::Relative(180), false, 6),
+                backref(Reference::Relative(170), true, 10),
+                backref(Reference::Absolute(0), false, 16),
+                None
+            ]
+        );
+
+        let opts = CompressOptions {
+            allowed_commands: CommandSet::all().without(CommandSet::INVERTED_RELATIVE),
+            relative_only: true,
+            ..Default::default()
+        };
+        let found =
+            compress::find_backreferences(&data, 500, &opts, &mut matcher::MatchIndex::new());
+        assert_eq!(
+            found,
+            [
+                backref(Reference::Relative(180), false, 6),
+                None,
+                None,
+                None
+            ]
+        );
+    }
+
+    #[test]
+    fn test_roundtrip_green_brinstar() {
+        let data = include_bytes!("green_brinstar_main_shaft.bin");
+
+        let decompressed = decompress(data).unwrap();
+        let recompressed = compress(&decompressed);
+        let redecompressed = decompress(&recompressed).unwrap();
+
+        assert_eq!(decompressed, redecompressed);
+        testing::assert_roundtrip(&decompressed);
+    }
+
+    #[test]
+    fn test_assert_roundtrip() {
+        testing::assert_roundtrip(&[]);
+        testing::assert_roundtrip(&random_bytes(0x800, 3));
+        let opts = CompressOptions {
+            emit_stop: false,
+            lazy: true,
+            ..CompressMode::SizeOptimal.into()
+        };
+        let compressed = testing::assert_roundtrip_with(&[1, 2, 3, 1, 2, 3, 1, 2, 3], &opts);
+        assert_ne!(compressed.last(), Some(&0xFF));
+    }
+
+    #[test]
+    fn test_encodings_equivalent() {
+        let src = include_bytes!("green_brinstar_main_shaft.bin");
+        let data = decompress(src).unwrap();
+        assert!(testing::encodings_equivalent(src, &compress(&data)));
+        assert!(testing::encodings_equivalent(
+            &compress(&data),
+            &compress_with_mode(&data, CompressMode::SizeOptimal)
+        ));
+
+        // A fill and the equivalent literals
+        assert!(testing::encodings_equivalent(
+            &[0x23, 7, 0xFF],
+            &[0x03, 7, 7, 7, 7, 0xFF]
+        ));
+        assert!(!testing::encodings_equivalent(
+            &[0x23, 7, 0xFF],
+            &[0x22, 7, 0xFF]
+        ));
+        // Invalid streams are never equivalent, even to themselves
+        assert!(!testing::encodings_equivalent(&[0x23, 7], &[0x23, 7]));
     }
 }
@@ -0,0 +1,108 @@
+use std::fs;
+use std::io::{self, Read, Write};
+use std::process::ExitCode;
+
+const USAGE: &str = "\
+Usage:
+    lznint compress <in> <out>
+    lznint decompress [--offset N] <in> <out>
+
+Use - as a path to read from stdin or write to stdout. N may be decimal or hexadecimal (0x...).";
+
+fn main() -> ExitCode {
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    match run(&args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(Error::Usage(msg)) => {
+            eprintln!("{msg}\n\n{USAGE}");
+            ExitCode::from(2)
+        }
+        Err(Error::Failed(msg)) => {
+            eprintln!("lznint: {msg}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+enum Error {
+    /// The command line was invalid.
+    Usage(String),
+    /// The operation failed.
+    Failed(String),
+}
+
+fn run(args: &[String]) -> Result<(), Error> {
+    let (command, args) = args
+        .split_first()
+        .ok_or_else(|| Error::Usage("Missing command".into()))?;
+
+    match command.as_str() {
+        "compress" => {
+            let [input, output] = args else {
+                return Err(Error::Usage("Expected an input and output path".into()));
+            };
+            let data = read_input(input)?;
+            write_output(output, &lznint::compress(&data))
+        }
+        "decompress" => {
+            let (offset, args) = match args {
+                [flag, offset, rest @ ..] if flag == "--offset" => {
+                    (Some(parse_offset(offset)?), rest)
+                }
+                _ => (None, args),
+            };
+            let [input, output] = args else {
+                return Err(Error::Usage("Expected an input and output path".into()));
+            };
+
+            let data = read_input(input)?;
+            let start = offset.unwrap_or(0);
+            let src = data.get(start..).ok_or_else(|| {
+                Error::Failed(format!(
+                    "Offset {start:#X} is past the end of the input ({:#X} bytes)",
+                    data.len()
+                ))
+            })?;
+            let (decompressed, consumed) = lznint::decompress_with_consumed(src)
+                .map_err(|e| Error::Failed(format!("{input}: {e}")))?;
+            write_output(output, &decompressed)?;
+
+            if offset.is_some() {
+                eprintln!(
+                    "Consumed {consumed:#X} bytes, ending at offset {:#X}",
+                    start + consumed
+                );
+            }
+            Ok(())
+        }
+        _ => Err(Error::Usage(format!("Unknown command '{command}'"))),
+    }
+}
+
+fn parse_offset(s: &str) -> Result<usize, Error> {
+    let result = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => usize::from_str_radix(hex, 16),
+        None => s.parse(),
+    };
+    result.map_err(|_| Error::Usage(format!("Invalid offset '{s}'")))
+}
+
+fn read_input(path: &str) -> Result<Vec<u8>, Error> {
+    let result = if path == "-" {
+        let mut data = Vec::new();
+        io::stdin().read_to_end(&mut data).map(|_| data)
+    } else {
+        fs::read(path)
+    };
+    result.map_err(|e| Error::Failed(format!("{path}: {e}")))
+}
+
+fn write_output(path: &str, data: &[u8]) -> Result<(), Error> {
+    let result = if path == "-" {
+        let mut stdout = io::stdout().lock();
+        stdout.write_all(data).and_then(|_| stdout.flush())
+    } else {
+        fs::write(path, data)
+    };
+    result.map_err(|e| Error::Failed(format!("{path}: {e}")))
+}
@@ -0,0 +1,65 @@
+use alloc::{vec, vec::Vec};
+
+/// Hash chains indexing the positions at which each 3-byte sequence occurs in the input, used to
+/// find candidate absolute backreferences without scanning the whole window.
+///
+/// Positions are added in increasing order as compression advances. Each hash bucket has a chain
+/// of positions, linked from most recent to oldest. Since different sequences can share a bucket,
+/// callers must verify each candidate position.
+#[derive(Debug, Clone)]
+pub(crate) struct MatchIndex {
+    /// The most recently indexed position in each bucket.
+    head: Vec<u32>,
+
+    /// For each indexed position, the previous position in the same bucket.
+    prev: Vec<u32>,
+}
+
+const NONE: u32 = u32::MAX;
+const HASH_BITS: u32 = 16;
+
+impl MatchIndex {
+    pub(crate) fn new() -> Self {
+        MatchIndex {
+            head: vec![NONE; 1 << HASH_BITS],
+            prev: Vec::new(),
+        }
+    }
+
+    /// Removes all indexed positions, keeping the allocated storage.
+    pub(crate) fn clear(&mut self) {
+        self.head.fill(NONE);
+        self.prev.clear();
+    }
+
+    /// Indexes every position before `end` that has not yet been indexed.
+    pub(crate) fn extend(&mut self, src: &[u8], end: usize) {
+        // The last two bytes of the input don't begin a 3-byte sequence, and can never begin a
+        // useful match
+        let end = core::cmp::min(end, src.len().saturating_sub(2));
+        for j in self.prev.len()..end {
+            let key = hash([src[j], src[j + 1], src[j + 2]]);
+            self.prev.push(self.head[key]);
+            self.head[key] = j as u32;
+        }
+    }
+
+    /// Returns the indexed positions that may begin with `seq`, from most recent to oldest.
+    pub(crate) fn positions(&self, seq: [u8; 3]) -> impl Iterator<Item = usize> + '_ {
+        let mut j = self.head[hash(seq)];
+        core::iter::from_fn(move || {
+            if j == NONE {
+                None
+            } else {
+                let result = j as usize;
+                j = self.prev[result];
+                Some(result)
+            }
+        })
+    }
+}
+
+fn hash(seq: [u8; 3]) -> usize {
+    let x = u32::from_le_bytes([seq[0], seq[1], seq[2], 0]);
+    (x.wrapping_mul(0x9E3779B1) >> (32 - HASH_BITS)) as usize
+}
@@ -0,0 +1,119 @@
+//! Reversible transforms that can make data compress better.
+//!
+//! These don't change the compressed format: run a transform on the data before compressing it,
+//! and its inverse after decompressing it.
+
+use alloc::vec::Vec;
+
+/// Rearranges 2bpp SNES tiles so that each bitplane is stored contiguously.
+///
+/// Each 8x8 tile is 16 bytes, with the two bitplanes of each row interleaved (row 0 plane 0, row 0
+/// plane 1, row 1 plane 0, ...). The output stores all 8 rows of the first plane, then all 8 rows
+/// of the second. Similar rows within a plane then end up next to each other, which often produces
+/// longer matches. A partial tile at the end of the input is left unchanged.
+///
+/// Reversed by [`interleave_2bpp`].
+pub fn deinterleave_2bpp(src: &[u8]) -> Vec<u8> {
+    shuffle(src, 16, planar_index_2bpp)
+}
+
+/// Reverses [`deinterleave_2bpp`], restoring the SNES 2bpp tile layout.
+pub fn interleave_2bpp(src: &[u8]) -> Vec<u8> {
+    unshuffle(src, 16, planar_index_2bpp)
+}
+
+/// Rearranges 4bpp SNES tiles so that each bitplane is stored contiguously.
+///
+/// Each 8x8 tile is 32 bytes: planes 0 and 1 interleaved by row (as in a 2bpp tile), followed by
+/// planes 2 and 3 interleaved by row. The output stores all 8 rows of each of the 4 planes in turn.
+/// A partial tile at the end of the input is left unchanged.
+///
+/// Reversed by [`interleave_4bpp`].
+pub fn deinterleave_4bpp(src: &[u8]) -> Vec<u8> {
+    shuffle(src, 32, planar_index_4bpp)
+}
+
+/// Reverses [`deinterleave_4bpp`], restoring the SNES 4bpp tile layout.
+pub fn interleave_4bpp(src: &[u8]) -> Vec<u8> {
+    unshuffle(src, 32, planar_index_4bpp)
+}
+
+/// Replaces each 16-bit little-endian word with its difference from the previous word.
+///
+/// The format's [`Incrementing`](crate::Command::Incrementing) command only counts up one byte at
+/// a time, so it can't encode tables of 16-bit values such as pointers, which are common in ROM
+/// data. After this transform, a table whose entries increase by a constant stride becomes a
+/// single repeated word, which compresses to one [`WordFill`](crate::Command::WordFill). The first
+/// word is stored as-is, and a trailing odd byte is left unchanged. Differences wrap around.
+///
+/// Reversed by [`delta_decode_words`].
+pub fn delta_encode_words(src: &[u8]) -> Vec<u8> {
+    let mut dst = src.to_vec();
+    let mut prev = 0u16;
+    for word in dst.chunks_exact_mut(2) {
+        let value = u16::from_le_bytes([word[0], word[1]]);
+        word.copy_from_slice(&value.wrapping_sub(prev).to_le_bytes());
+        prev = value;
+    }
+    dst
+}
+
+/// Reverses [`delta_encode_words`], restoring the original words from their differences.
+pub fn delta_decode_words(src: &[u8]) -> Vec<u8> {
+    let mut dst = src.to_vec();
+    let mut prev = 0u16;
+    for word in dst.chunks_exact_mut(2) {
+        prev = prev.wrapping_add(u16::from_le_bytes([word[0], word[1]]));
+        word.copy_from_slice(&prev.to_le_bytes());
+    }
+    dst
+}
+
+/// Reverses the order of the bytes.
+///
+/// This is for data consumed by a routine that reads (or writes) its buffer from the end to the
+/// start: compress the reversed data as usual, and decompress it as usual, then the reversed
+/// output appears in the original order when read backwards. The compressed format itself is
+/// unchanged, and decodes front to back. This is its own inverse.
+pub fn reverse_bytes(src: &[u8]) -> Vec<u8> {
+    src.iter().rev().copied().collect()
+}
+
+/// Returns where byte `i` of an interleaved 2bpp tile goes in the planar layout.
+fn planar_index_2bpp(i: usize) -> usize {
+    let (row, plane) = (i / 2, i % 2);
+    plane * 8 + row
+}
+
+/// Returns where byte `i` of an interleaved 4bpp tile goes in the planar layout.
+fn planar_index_4bpp(i: usize) -> usize {
+    (i / 16) * 16 + planar_index_2bpp(i % 16)
+}
+
+/// Moves byte `i` of each `tile_size`-byte tile to position `map(i)`.
+fn shuffle(src: &[u8], tile_size: usize, map: fn(usize) -> usize) -> Vec<u8> {
+    let mut dst = src.to_vec();
+    for (src, dst) in src
+        .chunks_exact(tile_size)
+        .zip(dst.chunks_exact_mut(tile_size))
+    {
+        for (i, &b) in src.iter().enumerate() {
+            dst[map(i)] = b;
+        }
+    }
+    dst
+}
+
+/// Moves the byte at position `map(i)` of each `tile_size`-byte tile back to position `i`.
+fn unshuffle(src: &[u8], tile_size: usize, map: fn(usize) -> usize) -> Vec<u8> {
+    let mut dst = src.to_vec();
+    for (src, dst) in src
+        .chunks_exact(tile_size)
+        .zip(dst.chunks_exact_mut(tile_size))
+    {
+        for (i, b) in dst.iter_mut().enumerate() {
+            *b = src[map(i)];
+        }
+    }
+    dst
+}
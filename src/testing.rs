@@ -0,0 +1,77 @@
+//! Assertions and checks for testing code that uses this crate.
+//!
+//! These check the codec's invariants on arbitrary data, so downstream crates can run their own
+//! inputs (or property-based and fuzz tests) against it.
+
+use alloc::vec::Vec;
+
+use crate::{compress_bound, compress_with, decompress, decompress_unterminated, CompressOptions};
+
+/// Compresses `data` with the default options, and asserts that:
+///
+/// - the output decompresses back to `data`,
+/// - the output fits within [`compress_bound`], and
+/// - compressing the decompressed data again produces exactly the same output.
+///
+/// The last property holds because the compressor is deterministic. It's only a property of this
+/// crate's output, though: recompressing a stream produced by another encoder (such as the data in
+/// the original game) generally doesn't reproduce that stream, even though it decompresses to the
+/// same data.
+///
+/// # Panics
+///
+/// Panics if any of the assertions fail.
+#[track_caller]
+pub fn assert_roundtrip(data: &[u8]) {
+    let compressed = assert_roundtrip_with(data, &CompressOptions::default());
+    assert!(
+        compressed.len() <= compress_bound(data.len()),
+        "compressed size {} exceeds compress_bound({})",
+        compressed.len(),
+        data.len()
+    );
+}
+
+/// Compresses `data` with the given options, and asserts that the output decompresses back to
+/// `data` and that compressing it again produces the same output. Returns the compressed data.
+///
+/// Streams compressed without [`emit_stop`](CompressOptions::emit_stop) are decompressed with
+/// [`decompress_unterminated`]. [`compress_bound`] is not checked, since it doesn't hold for every
+/// set of options.
+///
+/// # Panics
+///
+/// Panics if any of the assertions fail.
+#[track_caller]
+pub fn assert_roundtrip_with(data: &[u8], opts: &CompressOptions) -> Vec<u8> {
+    let compressed = compress_with(data, opts);
+    let decompressed = if opts.emit_stop {
+        decompress(&compressed)
+    } else {
+        decompress_unterminated(&compressed)
+    };
+    match decompressed {
+        Ok(decompressed) => assert!(
+            decompressed == data,
+            "decompressed data does not match the input"
+        ),
+        Err(e) => panic!("compressed data failed to decompress: {e}"),
+    }
+    assert!(
+        compress_with(data, opts) == compressed,
+        "compressing the same data twice produced different output"
+    );
+    compressed
+}
+
+/// Returns whether two compressed streams decompress to the same data, however differently they
+/// encode it.
+///
+/// This is the property to check when changing the compressor: the new output may differ from the
+/// old, but must be equivalent to it. Returns `false` if either stream fails to decompress.
+pub fn encodings_equivalent(a: &[u8], b: &[u8]) -> bool {
+    match (decompress(a), decompress(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}